@@ -0,0 +1,77 @@
+//! Throughput of the logging macros for records which the filter drops, across several threads.
+//!
+//! Run with `cargo bench --bench filtered`. Two cases are measured:
+//!
+//! - `max level`: the record is more verbose than any directive, so it is rejected by the cached
+//!   max level.
+//! - `directive`: another target is traced, so the record has to be looked up in the filter.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ std imports
+use std::hint::black_box;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+//}}}
+//{{{ dep imports
+use log::Level;
+use topohedral_tracing::{set_filter, topo_enabled, topo_log, Callsite, Target, TracingBuilder};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+const RECORDS_PER_THREAD: u32 = 2_000_000;
+
+//{{{ fun: run
+/// Logs filtered out trace records from `threads` threads at once, returning the elapsed time.
+fn run(threads: usize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                // The expansion of `trace!`, which is compiled out without `enable_trace`.
+                static CALLSITE: Callsite =
+                    Callsite::new(module_path!(), file!(), line!(), column!());
+                for i in 0..RECORDS_PER_THREAD {
+                    let target = "topohedral_linalg::dense";
+                    if topo_enabled(target, Level::Trace) {
+                        topo_log(
+                            target,
+                            Level::Trace,
+                            &CALLSITE,
+                            format_args!("residual {}", black_box(i)),
+                        );
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+//}}}
+//{{{ fun: main
+fn main() {
+    TracingBuilder::new()
+        .writer(Target::Pipe(Box::new(io::sink())))
+        .init()
+        .unwrap();
+
+    let cases = [
+        ("max level", "all=warn"),
+        ("directive", "all=warn,topohedral_mesh=trace"),
+    ];
+    for (name, directives) in cases {
+        set_filter(directives).unwrap();
+        for threads in [1, 2, 4, 8] {
+            let elapsed = run(threads);
+            let records = f64::from(RECORDS_PER_THREAD) * threads as f64;
+            println!(
+                "{:<10} {} threads: {:>8.2} ns/record, {:>8.1} Mrecords/s",
+                name,
+                threads,
+                elapsed.as_nanos() as f64 / f64::from(RECORDS_PER_THREAD),
+                records / elapsed.as_secs_f64() / 1e6,
+            );
+        }
+    }
+}
+//}}}
@@ -0,0 +1,300 @@
+//! Writing formatted records on a background thread fed by a bounded queue.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::writer::Writer;
+//}}}
+//{{{ std imports
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+//}}}
+//{{{ dep imports
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Overflow
+//{{{ enum: Overflow
+/// What a sink writing on a background thread does with a record when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wait until the background thread has made room, the default, so no record is lost.
+    #[default]
+    Block,
+    /// Discard the record being logged.
+    DropNewest,
+    /// Discard the oldest record in the queue to make room.
+    DropOldest,
+}
+//}}}
+//{{{ impl Overflow
+impl Overflow {
+    /// Parses the value of `TOPO_LOG_OVERFLOW`: `block`, `drop_newest` or `drop_oldest`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "block" => Ok(Overflow::Block),
+            "drop_newest" => Ok(Overflow::DropNewest),
+            "drop_oldest" => Ok(Overflow::DropOldest),
+            _ => Err("expected `block`, `drop_newest` or `drop_oldest`".to_string()),
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ collection: Background
+//{{{ struct: Background
+/// A thread writing the lines handed to it, so that logging never waits on a slow writer unless
+/// the queue is full and the overflow policy is [`Overflow::Block`].
+///
+/// When records are dropped, a line reporting how many is written in their place. Dropping the
+/// `Background` writes out the remaining lines and stops the thread.
+pub(crate) struct Background {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+//}}}
+//{{{ struct: Shared
+/// The state shared between the logging threads and the background thread.
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when lines are queued or the queue is closed.
+    queued: Condvar,
+    /// Signalled when the background thread takes lines off the queue or has written them.
+    progress: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+//}}}
+//{{{ struct: State
+#[derive(Default)]
+struct State {
+    lines: VecDeque<String>,
+    /// The number of lines dropped since the background thread last took lines off the queue.
+    dropped: u64,
+    /// Whether the background thread is writing lines it took off the queue.
+    writing: bool,
+    closed: bool,
+}
+//}}}
+//{{{ impl Shared
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+//}}}
+//{{{ impl Background
+impl Background {
+    /// Starts the thread writing to `writer` from a queue of `capacity` lines.
+    ///
+    /// `report` formats the line written when records have been dropped, given their number.
+    pub(crate) fn spawn(
+        writer: Arc<Writer>,
+        capacity: usize,
+        overflow: Overflow,
+        report: impl Fn(u64) -> String + Send + 'static,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            queued: Condvar::new(),
+            progress: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("topohedral-tracing".to_string())
+                .spawn(move || run(&shared, &writer, report))?
+        };
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues a line to be written, applying the overflow policy if the queue is full.
+    pub(crate) fn send(&self, line: String) {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        if state.lines.len() >= shared.capacity {
+            match shared.overflow {
+                Overflow::Block => {
+                    state = shared
+                        .progress
+                        .wait_while(state, |state| state.lines.len() >= shared.capacity)
+                        .unwrap_or_else(|err| err.into_inner());
+                }
+                Overflow::DropNewest => {
+                    state.dropped += 1;
+                    return;
+                }
+                Overflow::DropOldest => {
+                    state.lines.pop_front();
+                    state.dropped += 1;
+                }
+            }
+        }
+        state.lines.push_back(line);
+        drop(state);
+        shared.queued.notify_one();
+    }
+
+    /// Waits until every line queued so far has been handed to the writer.
+    pub(crate) fn wait(&self) {
+        let shared = &*self.shared;
+        let state = shared.lock();
+        let _state = shared
+            .progress
+            .wait_while(state, |state| !state.lines.is_empty() || state.writing)
+            .unwrap_or_else(|err| err.into_inner());
+    }
+}
+//}}}
+//{{{ impl Drop for Background
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ fun: run
+/// The loop of the background thread, which writes lines until the queue is closed and empty.
+fn run(shared: &Shared, writer: &Writer, report: impl Fn(u64) -> String) {
+    loop {
+        let mut state = shared
+            .queued
+            .wait_while(shared.lock(), |state| {
+                state.lines.is_empty() && !state.closed
+            })
+            .unwrap_or_else(|err| err.into_inner());
+        if state.lines.is_empty() {
+            return;
+        }
+        let lines = std::mem::take(&mut state.lines);
+        let dropped = std::mem::take(&mut state.dropped);
+        state.writing = true;
+        drop(state);
+        shared.progress.notify_all();
+
+        // The oldest records were dropped from before the lines taken, the newest after them.
+        if dropped > 0 && shared.overflow == Overflow::DropOldest {
+            let _ = writer.write_line(&report(dropped));
+        }
+        for line in &lines {
+            let _ = writer.write_line(line);
+        }
+        if dropped > 0 && shared.overflow == Overflow::DropNewest {
+            let _ = writer.write_line(&report(dropped));
+        }
+
+        shared.lock().writing = false;
+        shared.progress.notify_all();
+    }
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    /// A pipe which holds up every write until it is opened.
+    #[derive(Clone, Default)]
+    struct Gate {
+        open: Arc<(Mutex<bool>, Condvar)>,
+        buffer: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Gate {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (open, opened) = &*self.open;
+            let _open = opened.wait_while(open.lock().unwrap(), |open| !*open);
+            self.buffer.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Gate {
+        fn open(&self) {
+            *self.open.0.lock().unwrap() = true;
+            self.open.1.notify_all();
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8(self.buffer.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    /// Starts a background writer with a queue of two lines, which is stuck writing `first`.
+    fn stuck(overflow: Overflow) -> (Gate, Background) {
+        let gate = Gate::default();
+        let writer = Arc::new(Writer::Pipe(Mutex::new(Box::new(gate.clone()))));
+        let background =
+            Background::spawn(writer, 2, overflow, |n| format!("dropped {}", n)).unwrap();
+        background.send("first".to_string());
+        while !background.shared.lock().writing {
+            thread::yield_now();
+        }
+        (gate, background)
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let (gate, background) = stuck(Overflow::DropOldest);
+        for line in ["second", "third", "fourth"] {
+            background.send(line.to_string());
+        }
+        gate.open();
+        background.wait();
+        assert_eq!(gate.contents(), "first\ndropped 1\nthird\nfourth\n");
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (gate, background) = stuck(Overflow::DropNewest);
+        for line in ["second", "third", "fourth", "fifth"] {
+            background.send(line.to_string());
+        }
+        gate.open();
+        drop(background);
+        assert_eq!(gate.contents(), "first\nsecond\nthird\ndropped 2\n");
+    }
+
+    #[test]
+    fn test_block() {
+        let (gate, background) = stuck(Overflow::Block);
+        thread::scope(|scope| {
+            let sender = scope.spawn(|| {
+                for line in ["second", "third", "fourth"] {
+                    background.send(line.to_string());
+                }
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!sender.is_finished());
+            gate.open();
+        });
+        background.wait();
+        assert_eq!(gate.contents(), "first\nsecond\nthird\nfourth\n");
+    }
+
+    #[test]
+    fn test_parse_overflow() {
+        assert_eq!(Overflow::parse("drop_oldest"), Ok(Overflow::DropOldest));
+        assert!(Overflow::parse("drop").is_err());
+    }
+}
+//}}}
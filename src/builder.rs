@@ -0,0 +1,1054 @@
+//! Programmatic configuration of the tracing system.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::background::{Background, Overflow};
+use crate::file::{FileTarget, Rotation};
+use crate::filter::{Filter, FilterSource};
+use crate::format::{Format, Origin, Style, Template, Timestamp};
+use crate::max_level;
+use crate::reload::spawn_watcher;
+use crate::sink::Sink;
+use crate::writer::{Target, Writer};
+use crate::{InitError, TopoHedralLogger, LOGGER};
+//}}}
+//{{{ std imports
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+//}}}
+//{{{ dep imports
+use arc_swap::ArcSwap;
+use log::{Level, LevelFilter, Record};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: constants
+/// Suffixes of the settings variables, which cannot be used as sink names.
+const RESERVED_SINK_NAMES: [&str; 12] = [
+    "FORMAT", "TIME", "STYLE", "FILE", "ROTATE", "KEEP", "COMPRESS", "QUEUE", "OVERFLOW", "DEDUP",
+    "SEED", "SINKS",
+];
+//}}}
+//{{{ collection: TracingBuilder
+//{{{ struct: TracingBuilder
+/// Builder used to configure and install the logger from code.
+///
+/// Filter settings are applied in the order the methods are called, so a later setting for a
+/// target replaces an earlier one. The `TOPO_LOG` environment variable is only read if requested
+/// with [`TracingBuilder::env_var`], or when starting from [`TracingBuilder::from_env`].
+///
+/// The filter, writer and format set here are those of the main sink. Further sinks, each with
+/// their own settings, are added with [`TracingBuilder::sink`].
+///
+/// The settings are kept by the installed logger, so [`reload`](crate::reload) can later read the
+/// environment variables and filter files again.
+///
+/// ```no_run
+/// use topohedral_tracing::{LevelFilter, TracingBuilder};
+///
+/// TracingBuilder::new()
+///     .default_level(LevelFilter::Warn)
+///     .filter("topohedral_mesh", LevelFilter::Debug)
+///     .env_var("TOPO_LOG")
+///     .init()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TracingBuilder {
+    main: SinkBuilder,
+    sinks: Vec<SinkBuilder>,
+    strict: bool,
+    watch: Option<Duration>,
+    /// Invalid settings read from the environment, reported when the logger is built.
+    errors: Vec<InitError>,
+}
+//}}}
+//{{{ impl Default for TracingBuilder
+impl Default for TracingBuilder {
+    fn default() -> Self {
+        Self {
+            main: SinkBuilder::new(""),
+            sinks: Vec::new(),
+            strict: false,
+            watch: None,
+            errors: Vec::new(),
+        }
+    }
+}
+//}}}
+//{{{ impl TracingBuilder
+impl TracingBuilder {
+    /// Creates a builder which logs nothing to stderr until filters are added.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a builder which reads its settings from the environment.
+    ///
+    /// The settings are read from these variables, where set:
+    ///
+    /// - `TOPO_LOG`: the filter directives.
+    /// - `TOPO_LOG_FORMAT`: `json` or a format template.
+    /// - `TOPO_LOG_TIME`: the timestamp, one of `none`, `utc`, `local` or `uptime`.
+    /// - `TOPO_LOG_STYLE`: whether to color the level, one of `auto`, `always` or `never`.
+    /// - `TOPO_LOG_FILE`: the path of a file to write to instead of stderr.
+    /// - `TOPO_LOG_ROTATE`: when to rotate the file, `never`, `hourly`, `daily` or a size such as
+    ///   `10MB`.
+    /// - `TOPO_LOG_KEEP`: how many rotated files to keep.
+    /// - `TOPO_LOG_COMPRESS`: `gzip` to compress rotated files, or `none`.
+    /// - `TOPO_LOG_QUEUE`: the number of records queued for a background thread writing them.
+    /// - `TOPO_LOG_OVERFLOW`: what to do when the queue is full, one of `block`, `drop_newest` or
+    ///   `drop_oldest`.
+    /// - `TOPO_LOG_DEDUP`: `on` to suppress repeated identical records, or `off`.
+    /// - `TOPO_LOG_SEED`: a number shifting which records sampled directives keep.
+    /// - `TOPO_LOG_SINKS`: a comma separated list of further sinks, each read with
+    ///   [`SinkBuilder::from_env`].
+    ///
+    /// This is the configuration used by [`init`](crate::init).
+    pub fn from_env() -> Self {
+        let mut builder = Self::new();
+        builder.main = builder.main.read_env("TOPO_LOG");
+        if let Some(names) = env_string("TOPO_LOG_SINKS", &mut builder.errors) {
+            for name in names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                let upper = name.to_ascii_uppercase();
+                let reason = if !upper
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                {
+                    format!("sink name `{}` is not alphanumeric", name)
+                } else if RESERVED_SINK_NAMES.contains(&upper.as_str()) {
+                    format!("sink name `{}` is reserved", name)
+                } else {
+                    builder.sinks.push(SinkBuilder::from_env(name));
+                    continue;
+                };
+                invalid_env("TOPO_LOG_SINKS", &names, reason, &mut builder.errors);
+            }
+        }
+        builder
+    }
+
+    /// Sets the level of `target` and every module below it.
+    ///
+    /// As in `TOPO_LOG`, the target `all` sets the default level.
+    pub fn filter(mut self, target: &str, level: LevelFilter) -> Self {
+        self.main = self.main.filter(target, level);
+        self
+    }
+
+    /// Sets the level of targets not covered by any other filter.
+    pub fn default_level(mut self, level: LevelFilter) -> Self {
+        self.main = self.main.default_level(level);
+        self
+    }
+
+    /// Adds the filters from a string using the `TOPO_LOG` syntax.
+    ///
+    /// Malformed directives are reported when the logger is installed.
+    pub fn parse_directives(mut self, directives: &str) -> Self {
+        self.main = self.main.parse_directives(directives);
+        self
+    }
+
+    /// Adds the filters from the environment variable `name`, if it is set.
+    ///
+    /// The variable is read when the logger is installed and again on every reload, but it keeps
+    /// its place in the order, so filters added after this call still take precedence.
+    pub fn env_var(mut self, name: &str) -> Self {
+        self.main = self.main.env_var(name);
+        self
+    }
+
+    /// Adds the filters from the file at `path`, if it exists.
+    ///
+    /// The file uses the `TOPO_LOG` syntax, with directives separated by commas or new lines and
+    /// `#` starting a comment. Like [`TracingBuilder::env_var`] it is read again on every reload.
+    pub fn config_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.main = self.main.config_file(path);
+        self
+    }
+
+    /// Reloads the filter whenever its environment variables or files change.
+    ///
+    /// A background thread checks for changes every `interval`. Malformed directives are reported
+    /// on stderr and leave the previous filter in effect.
+    pub fn watch(mut self, interval: Duration) -> Self {
+        self.watch = Some(interval);
+        self
+    }
+
+    /// Sets where the records are written, stderr by default.
+    pub fn writer(mut self, target: Target) -> Self {
+        self.main = self.main.writer(target);
+        self
+    }
+
+    /// Sets the layout of every record from a template.
+    ///
+    /// Placeholders are written `{name}` or `{name:width}`, where the width may be prefixed by `<`
+    /// or `>` to align left, the default, or right, and `{{` and `}}` stand for literal braces.
+    /// The placeholders are `time`, `level`, `thread`, `thread_id`, `thread_name`, `module`, `file`,
+    /// `line`, `column`, `location`, `target` and `msg`. A malformed template is reported when the
+    /// logger is installed.
+    ///
+    /// The default is `[{level:5} - {thread:3} - {module}:{line}] {msg}`, with `{time} - ` after
+    /// the bracket when a timestamp is enabled.
+    pub fn format(mut self, template: &str) -> Self {
+        self.main = self.main.format(template);
+        self
+    }
+
+    /// Writes every record as a single line JSON object instead of text.
+    ///
+    /// The object has the fields `time`, `level`, `target`, `module`, `file`, `line`, `column`,
+    /// `thread`, `thread_name` and `msg`, with any key-values of the record nested in a `fields`
+    /// object. Unless set otherwise, the time is in UTC.
+    pub fn json(mut self) -> Self {
+        self.main = self.main.json();
+        self
+    }
+
+    /// Sets the time shown at the start of every record, none by default for text.
+    ///
+    /// With a custom format template the time is shown wherever `{time}` appears.
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.main = self.main.timestamp(timestamp);
+        self
+    }
+
+    /// Sets whether the level is colored.
+    ///
+    /// By default it is only colored when writing to stderr or stdout attached to a terminal,
+    /// unless the `NO_COLOR` environment variable is set, or when `CLICOLOR_FORCE` is set.
+    /// Files, pipes and JSON records are never colored, even with [`Style::Always`].
+    pub fn style(mut self, style: Style) -> Self {
+        self.main = self.main.style(style);
+        self
+    }
+
+    /// Writes the records on a background thread, queueing up to `capacity` of them.
+    ///
+    /// Records are still filtered and formatted by the thread logging them, but are then handed
+    /// to the background thread so that logging does not wait on a slow terminal or disk. Call
+    /// [`flush`](crate::flush), or keep a [`FlushGuard`](crate::FlushGuard), so that the queued
+    /// records are written before the process exits.
+    pub fn queue(mut self, capacity: usize) -> Self {
+        self.main = self.main.queue(capacity);
+        self
+    }
+
+    /// Sets what happens to a record logged while the queue is full, blocking by default.
+    ///
+    /// When records are dropped a warning saying how many takes their place in the output. This
+    /// has no effect unless [`TracingBuilder::queue`] is set.
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.main = self.main.overflow(overflow);
+        self
+    }
+
+    /// Sets whether repeats of a record are suppressed, off by default.
+    ///
+    /// A record which formats the same as the one written before it, apart from its timestamp, is
+    /// then only counted. Once a different record arrives, or the logger is flushed, the count is
+    /// written as a copy of the repeated record, key-values included, ending in
+    /// `(repeated N times)`. The first record after a flush is always written.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.main = self.main.dedup(dedup);
+        self
+    }
+
+    /// Sets the seed of the directives sampling records, such as `topohedral_linalg=trace@0.01`,
+    /// 0 by default.
+    ///
+    /// A sampled directive keeps an evenly spread fraction of the records it covers at its level,
+    /// counting them as they are logged. The seed shifts which ones, so a run logging the same
+    /// records in the same order with the same seed keeps the same records.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.main = self.main.seed(seed);
+        self
+    }
+
+    /// Adds a sink which receives every record enabled by its own filter.
+    ///
+    /// ```no_run
+    /// use topohedral_tracing::{
+    ///     FileTarget, LevelFilter, SinkBuilder, Target, TracingBuilder,
+    /// };
+    ///
+    /// TracingBuilder::new()
+    ///     .default_level(LevelFilter::Warn)
+    ///     .sink(
+    ///         SinkBuilder::new("geom")
+    ///             .filter("topohedral_geom", LevelFilter::Trace)
+    ///             .writer(Target::File(FileTarget::new("geom.log"))),
+    ///     )
+    ///     .init()
+    ///     .unwrap();
+    /// ```
+    pub fn sink(mut self, sink: SinkBuilder) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Makes [`TracingBuilder::init`] fail on malformed directives instead of skipping them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Installs the configured logger as the global `log` logger.
+    ///
+    /// Returns an error if a global logger has already been installed, or in strict mode if any
+    /// directive or setting read from the environment was malformed. Otherwise these are reported
+    /// in a single line on stderr.
+    ///
+    /// Nothing is built once the logger of this crate has been installed, so a second call opens
+    /// no files, spawns no threads and prints nothing before returning the error.
+    pub fn init(self) -> Result<(), InitError> {
+        let watch = self.watch;
+        let installed = match LOGGER.get() {
+            Some(logger) => logger,
+            None => {
+                let mut logger = Some(self.build()?);
+                LOGGER.get_or_init(|| logger.take().unwrap())
+            }
+        };
+        log::set_logger(installed)?;
+
+        max_level::set(installed.filter_max_level());
+        if let Some(interval) = watch {
+            spawn_watcher(installed, interval);
+        }
+        Ok(())
+    }
+
+    /// Builds the logger without installing it.
+    pub(crate) fn build(self) -> Result<TopoHedralLogger, InitError> {
+        let start = Instant::now();
+        let mut sinks = vec![self.main];
+        sinks.extend(self.sinks);
+
+        let mut builds = Vec::with_capacity(sinks.len());
+        let mut errors = Vec::new();
+        for sink in &mut sinks {
+            let mut build = Filter::from_sources(&sink.sources)?;
+            errors.extend(build.errors.drain(..).map(InitError::from));
+            errors.append(&mut sink.errors);
+            builds.push(build);
+        }
+        errors.extend(self.errors);
+        if !errors.is_empty() {
+            if self.strict {
+                return Err(errors.swap_remove(0));
+            }
+            let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            eprintln!(
+                "topohedral-tracing: ignoring invalid settings: {}",
+                errors.join("; ")
+            );
+        }
+
+        let mut inputs = Vec::with_capacity(sinks.len());
+        let mut opened = Vec::with_capacity(sinks.len());
+        for (sink, build) in sinks.into_iter().zip(builds) {
+            inputs.push(build.inputs);
+            opened.push(sink.open(build.filter, start)?);
+        }
+        Ok(TopoHedralLogger::new(opened, inputs, start))
+    }
+}
+//}}}
+//}}}
+//{{{ collection: SinkBuilder
+//{{{ struct: SinkBuilder
+/// Builder for a further sink of the logger, added with [`TracingBuilder::sink`].
+///
+/// A sink has its own filter, writer, format and color settings, which are set in the same way as
+/// those of the main sink on [`TracingBuilder`]. A record is written to every sink whose filter
+/// enables it. A sink starts out logging nothing, so it needs at least one filter.
+#[derive(Debug)]
+pub struct SinkBuilder {
+    name: String,
+    sources: Vec<FilterSource>,
+    target: Target,
+    format: Option<Format>,
+    timestamp: Option<Timestamp>,
+    style: Style,
+    /// The capacity of the queue of the background thread, if there is one.
+    queue: Option<usize>,
+    overflow: Overflow,
+    /// Whether repeats of a record are suppressed.
+    dedup: bool,
+    seed: u64,
+    /// Invalid settings read from the environment, reported when the logger is built.
+    errors: Vec<InitError>,
+}
+//}}}
+//{{{ impl SinkBuilder
+impl SinkBuilder {
+    /// Creates a sink writing to stderr, which is addressed as `name` by
+    /// [`set_sink_filter`](crate::set_sink_filter).
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            sources: Vec::new(),
+            target: Target::default(),
+            format: None,
+            timestamp: None,
+            style: Style::Auto,
+            queue: None,
+            overflow: Overflow::Block,
+            dedup: false,
+            seed: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Creates a sink which reads its settings from the `TOPO_LOG_<NAME>` family of environment
+    /// variables, where `<NAME>` is `name` in upper case.
+    ///
+    /// The filter is read from `TOPO_LOG_<NAME>` and the other settings from the variables
+    /// described in [`TracingBuilder::from_env`] with `_<NAME>` inserted after `TOPO_LOG`, e.g.
+    /// `TOPO_LOG_GEOM_FILE` for the sink `geom`.
+    pub fn from_env(name: &str) -> Self {
+        let prefix = format!("TOPO_LOG_{}", name.to_ascii_uppercase());
+        Self::new(name).read_env(&prefix)
+    }
+
+    /// Reads the filter from the variable `prefix` and the other settings from the variables
+    /// starting with `prefix`.
+    fn read_env(mut self, prefix: &str) -> Self {
+        self = self.env_var(prefix);
+        let errors = &mut self.errors;
+        if let Some(value) = env_string(&format!("{}_FORMAT", prefix), errors) {
+            match Format::parse(&value) {
+                Ok(format) => self.format = Some(format),
+                Err(err) => errors.push(err.into()),
+            }
+        }
+        if let Some(timestamp) = env_setting(&format!("{}_TIME", prefix), errors, Timestamp::parse)
+        {
+            self.timestamp = Some(timestamp);
+        }
+        if let Some(style) = env_setting(&format!("{}_STYLE", prefix), errors, Style::parse) {
+            self.style = style;
+        }
+        if let Some(path) = env_string(&format!("{}_FILE", prefix), errors) {
+            let mut file = FileTarget::new(path);
+            if let Some(rotation) =
+                env_setting(&format!("{}_ROTATE", prefix), errors, Rotation::parse)
+            {
+                file = file.rotation(rotation);
+            }
+            if let Some(keep) = env_setting(&format!("{}_KEEP", prefix), errors, |value| {
+                value
+                    .parse()
+                    .map_err(|_| "expected a number of files".to_string())
+            }) {
+                file = file.keep(keep);
+            }
+            if let Some(compress) =
+                env_setting(
+                    &format!("{}_COMPRESS", prefix),
+                    errors,
+                    |value| match value {
+                        "gzip" => Ok(true),
+                        "none" => Ok(false),
+                        _ => Err("expected `gzip` or `none`".to_string()),
+                    },
+                )
+            {
+                file = file.compress(compress);
+            }
+            self.target = Target::File(file);
+        }
+        if let Some(capacity) = env_setting(&format!("{}_QUEUE", prefix), errors, |value| {
+            value
+                .parse()
+                .ok()
+                .filter(|capacity| *capacity > 0)
+                .ok_or_else(|| "expected a positive number of records".to_string())
+        }) {
+            self.queue = Some(capacity);
+        }
+        if let Some(overflow) =
+            env_setting(&format!("{}_OVERFLOW", prefix), errors, Overflow::parse)
+        {
+            self.overflow = overflow;
+        }
+        if let Some(dedup) =
+            env_setting(&format!("{}_DEDUP", prefix), errors, |value| match value {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => Err("expected `on` or `off`".to_string()),
+            })
+        {
+            self.dedup = dedup;
+        }
+        if let Some(seed) = env_setting(&format!("{}_SEED", prefix), errors, |value| {
+            value
+                .parse()
+                .map_err(|_| "expected a non-negative number".to_string())
+        }) {
+            self.seed = seed;
+        }
+        self
+    }
+
+    /// Sets the level of `target` and every module below it, see [`TracingBuilder::filter`].
+    pub fn filter(mut self, target: &str, level: LevelFilter) -> Self {
+        self.sources
+            .push(FilterSource::Level(target.to_string(), level));
+        self
+    }
+
+    /// Sets the level of targets not covered by any other filter.
+    pub fn default_level(self, level: LevelFilter) -> Self {
+        self.filter("all", level)
+    }
+
+    /// Adds the filters from a string using the `TOPO_LOG` syntax.
+    pub fn parse_directives(mut self, directives: &str) -> Self {
+        self.sources
+            .push(FilterSource::Directives(directives.to_string()));
+        self
+    }
+
+    /// Adds the filters from the environment variable `name`, see [`TracingBuilder::env_var`].
+    pub fn env_var(mut self, name: &str) -> Self {
+        self.sources.push(FilterSource::Env(name.to_string()));
+        self
+    }
+
+    /// Adds the filters from the file at `path`, see [`TracingBuilder::config_file`].
+    pub fn config_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.sources
+            .push(FilterSource::File(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Sets where the records are written, stderr by default.
+    pub fn writer(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Sets the layout of every record from a template, see [`TracingBuilder::format`].
+    pub fn format(mut self, template: &str) -> Self {
+        match Template::parse(template) {
+            Ok(template) => self.format = Some(Format::Text(template)),
+            Err(err) => self.errors.push(err.into()),
+        }
+        self
+    }
+
+    /// Writes every record as a single line JSON object, see [`TracingBuilder::json`].
+    pub fn json(mut self) -> Self {
+        self.format = Some(Format::Json);
+        self
+    }
+
+    /// Sets the time shown at the start of every record, none by default for text.
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets whether the level is colored, see [`TracingBuilder::style`].
+    pub fn style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Writes the records on a background thread, see [`TracingBuilder::queue`].
+    pub fn queue(mut self, capacity: usize) -> Self {
+        self.queue = Some(capacity);
+        self
+    }
+
+    /// Sets what happens to a record logged while the queue is full, see
+    /// [`TracingBuilder::overflow`].
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Sets whether repeats of a record are suppressed, see [`TracingBuilder::dedup`].
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Sets the seed of the directives sampling records, see [`TracingBuilder::seed`].
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Opens the writer and builds the sink around its filter.
+    ///
+    /// `start` is the time uptime is counted from, for the records reporting dropped records.
+    fn open(self, filter: Filter, start: Instant) -> Result<Sink, InitError> {
+        let timestamp = self.timestamp.unwrap_or(match self.format {
+            Some(Format::Json) => Timestamp::Utc,
+            _ => Timestamp::None,
+        });
+        let format = self
+            .format
+            .unwrap_or_else(|| Format::Text(Template::default_for(timestamp)));
+        let writer = Arc::new(Writer::open(self.target)?);
+        let color = self.style.colors(writer.is_console(), writer.is_terminal());
+        let background = match self.queue {
+            Some(capacity) => {
+                let format = format.clone();
+                let report = move |dropped| {
+                    let mut line = String::new();
+                    format.render(
+                        &Record::builder()
+                            .args(format_args!(
+                                "dropped {} records as the queue was full",
+                                dropped
+                            ))
+                            .level(Level::Warn)
+                            .target("topohedral_tracing")
+                            .module_path_static(Some(module_path!()))
+                            .build(),
+                        None,
+                        &Origin::current(),
+                        timestamp.now(start).as_ref(),
+                        color,
+                        &mut line,
+                    );
+                    line
+                };
+                let writer = Arc::clone(&writer);
+                Some(Background::spawn(writer, capacity, self.overflow, report)?)
+            }
+            None => None,
+        };
+
+        Ok(Sink {
+            name: self.name,
+            filter: ArcSwap::from_pointee(filter),
+            sources: self.sources,
+            format,
+            timestamp,
+            color,
+            writer,
+            background,
+            dedup: self.dedup.then(|| Mutex::new(None)),
+            seed: self.seed,
+        })
+    }
+}
+//}}}
+//}}}
+//{{{ fun: env_setting
+/// Reads and parses the setting in the environment variable `var`, if it is set.
+///
+/// An invalid value is recorded in `errors` to be reported when the logger is built.
+fn env_setting<T>(
+    var: &str,
+    errors: &mut Vec<InitError>,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> Option<T> {
+    let value = env_string(var, errors)?;
+    match parse(&value) {
+        Ok(setting) => Some(setting),
+        Err(reason) => {
+            invalid_env(var, &value, reason, errors);
+            None
+        }
+    }
+}
+//}}}
+//{{{ fun: env_string
+/// Reads the environment variable `var`, if it is set.
+///
+/// A value which is not valid Unicode is recorded in `errors` to be reported when the logger is
+/// built.
+fn env_string(var: &str, errors: &mut Vec<InitError>) -> Option<String> {
+    match std::env::var_os(var)?.into_string() {
+        Ok(value) => Some(value),
+        Err(value) => {
+            let value = value.to_string_lossy().into_owned();
+            invalid_env(var, &value, "not valid unicode".to_string(), errors);
+            None
+        }
+    }
+}
+//}}}
+//{{{ fun: invalid_env
+/// Records an invalid setting read from the environment.
+fn invalid_env(var: &str, value: &str, reason: String, errors: &mut Vec<InitError>) {
+    errors.push(InitError::InvalidEnv {
+        var: var.to_string(),
+        value: value.to_string(),
+        reason,
+    });
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_util::Buffer;
+    use log::{Level, Log, Metadata, Record};
+
+    fn log(logger: &TopoHedralLogger, target: &str, level: Level, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{}", msg))
+                .level(level)
+                .target(target)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_builder_filters_and_writer() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Warn)
+            .parse_directives("topohedral_mesh=trace")
+            .filter("topohedral_mesh::kernel", LevelFilter::Off)
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .build()
+            .unwrap();
+
+        log(&logger, "topohedral_geom", Level::Info, "geom info");
+        log(&logger, "topohedral_geom", Level::Warn, "geom warn");
+        log(
+            &logger,
+            "topohedral_mesh::delaunay",
+            Level::Trace,
+            "mesh trace",
+        );
+        log(
+            &logger,
+            "topohedral_mesh::kernel",
+            Level::Error,
+            "kernel error",
+        );
+
+        let contents = buffer.contents();
+        assert!(!contents.contains("geom info"));
+        assert!(contents.contains("geom warn"));
+        assert!(contents.contains("mesh trace"));
+        assert!(!contents.contains("kernel error"));
+        assert_eq!(contents.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_builder_timestamp() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .timestamp(Timestamp::Uptime)
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .build()
+            .unwrap();
+
+        log(&logger, "topohedral_geom", Level::Info, "geom info");
+
+        let contents = buffer.contents();
+        let time = contents[1..].split(' ').next().unwrap();
+        assert!(time.parse::<f64>().is_ok(), "{}", contents);
+        assert!(contents.ends_with("] geom info\n"));
+    }
+
+    #[test]
+    fn test_builder_format() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .format("{target} {msg}")
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .build()
+            .unwrap();
+
+        log(&logger, "topohedral_geom", Level::Info, "geom info");
+
+        assert_eq!(buffer.contents(), "topohedral_geom geom info\n");
+    }
+
+    #[test]
+    fn test_builder_json() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .json()
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .build()
+            .unwrap();
+
+        log(&logger, "topohedral_geom", Level::Info, "geom info");
+
+        let contents = buffer.contents();
+        assert!(contents.starts_with("{\"time\":\""), "{}", contents);
+        assert!(
+            contents.ends_with(",\"msg\":\"geom info\"}\n"),
+            "{}",
+            contents
+        );
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_builder_file() {
+        let path = std::env::temp_dir().join(format!(
+            "topohedral-tracing-builder-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .format("{level} {msg}")
+            .writer(Target::File(FileTarget::new(&path)))
+            .build()
+            .unwrap();
+
+        log(&logger, "topohedral_geom", Level::Info, "geom info");
+        logger.flush();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "INFO geom info\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_builder_queue() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .format("{thread_name} {msg}")
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .queue(2)
+            .build()
+            .unwrap();
+
+        for i in 0..10 {
+            log(
+                &logger,
+                "topohedral_geom",
+                Level::Info,
+                &format!("record {}", i),
+            );
+        }
+        logger.flush();
+
+        let thread = std::thread::current().name().unwrap().to_string();
+        let expected: String = (0..10)
+            .map(|i| format!("{} record {}\n", thread, i))
+            .collect();
+        assert_eq!(buffer.contents(), expected);
+    }
+
+    #[test]
+    fn test_builder_dedup() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .format("{level} {msg}")
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .dedup(true)
+            .build()
+            .unwrap();
+
+        for _ in 0..5 {
+            logger.log(
+                &Record::builder()
+                    .args(format_args!("matrix is singular"))
+                    .level(Level::Warn)
+                    .target("topohedral_linalg")
+                    .key_values(&[("pivot", 3)])
+                    .build(),
+            );
+        }
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+        logger.flush();
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+        logger.flush();
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+        logger.flush();
+
+        assert_eq!(
+            buffer.contents(),
+            "WARN matrix is singular pivot=3\n\
+             WARN matrix is singular (repeated 4 times) pivot=3\n\
+             INFO converged\n\
+             INFO converged (repeated once)\n\
+             INFO converged\n\
+             INFO converged (repeated 2 times)\n\
+             INFO converged\n"
+        );
+    }
+
+    #[test]
+    fn test_builder_dedup_threads() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Info)
+            .format("{thread} {msg}")
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .dedup(true)
+            .build()
+            .unwrap();
+
+        let logger = &logger;
+        std::thread::scope(|scope| {
+            let spawn = |name: &str, msg: &'static str, times: usize| {
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn_scoped(scope, move || {
+                        for _ in 0..times {
+                            log(logger, "topohedral_linalg", Level::Warn, msg);
+                        }
+                    })
+                    .unwrap()
+                    .join()
+                    .unwrap();
+            };
+            spawn("solver-1", "pivot small", 3);
+            spawn("solver-2", "converged", 1);
+        });
+
+        assert_eq!(
+            buffer.contents(),
+            "solver-1 pivot small\n\
+             solver-1 pivot small (repeated 2 times)\n\
+             solver-2 converged\n"
+        );
+    }
+
+    #[test]
+    fn test_builder_sampling() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .parse_directives("all=info,topohedral_linalg=trace@1/4")
+            .format("{level} {msg}")
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .seed(2)
+            .build()
+            .unwrap();
+
+        for i in 0..8 {
+            let metadata = Metadata::builder()
+                .level(Level::Trace)
+                .target("topohedral_linalg")
+                .build();
+            assert!(logger.enabled(&metadata));
+            log(
+                &logger,
+                "topohedral_linalg",
+                Level::Trace,
+                &format!("pivot {}", i),
+            );
+        }
+        log(&logger, "topohedral_linalg", Level::Info, "converged");
+
+        assert_eq!(
+            buffer.contents(),
+            "TRACE pivot 2\nTRACE pivot 6\nINFO converged\n"
+        );
+    }
+
+    #[test]
+    fn test_builder_sinks() {
+        let console = Buffer::default();
+        let geom = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Warn)
+            .format("{level} {msg}")
+            .writer(Target::Pipe(Box::new(console.clone())))
+            .sink(
+                SinkBuilder::new("geom")
+                    .filter("topohedral_geom", LevelFilter::Trace)
+                    .format("{target} {msg}")
+                    .writer(Target::Pipe(Box::new(geom.clone()))),
+            )
+            .build()
+            .unwrap();
+
+        log(&logger, "topohedral_geom", Level::Trace, "geom trace");
+        log(&logger, "topohedral_geom", Level::Warn, "geom warn");
+        log(&logger, "topohedral_mesh", Level::Info, "mesh info");
+
+        assert_eq!(console.contents(), "WARN geom warn\n");
+        assert_eq!(
+            geom.contents(),
+            "topohedral_geom geom trace\ntopohedral_geom geom warn\n"
+        );
+        assert_eq!(
+            logger.max_level.load(std::sync::atomic::Ordering::Relaxed),
+            LevelFilter::Trace as usize
+        );
+    }
+
+    #[test]
+    fn test_sink_from_env() {
+        std::env::set_var("TOPO_LOG_BUILDER_TEST", "topohedral_geom=debug");
+        std::env::set_var("TOPO_LOG_BUILDER_TEST_FORMAT", "{msg}");
+        std::env::set_var("TOPO_LOG_BUILDER_TEST_KEEP", "3");
+        std::env::set_var("TOPO_LOG_BUILDER_TEST_QUEUE", "64");
+        std::env::set_var("TOPO_LOG_BUILDER_TEST_OVERFLOW", "drop_oldest");
+        std::env::set_var("TOPO_LOG_BUILDER_TEST_DEDUP", "on");
+        std::env::set_var("TOPO_LOG_BUILDER_TEST_SEED", "42");
+        let sink = SinkBuilder::from_env("builder_test");
+
+        assert!(matches!(sink.format, Some(Format::Text(_))));
+        assert!(matches!(sink.target, Target::Stderr));
+        assert_eq!(sink.queue, Some(64));
+        assert_eq!(sink.overflow, Overflow::DropOldest);
+        assert!(sink.dedup);
+        assert_eq!(sink.seed, 42);
+        assert!(sink.errors.is_empty(), "{:?}", sink.errors);
+        let build = Filter::from_sources(&sink.sources).unwrap();
+        assert_eq!(
+            build.filter.target_level("topohedral_geom"),
+            Some(LevelFilter::Debug)
+        );
+    }
+
+    #[test]
+    fn test_builder_later_settings_win() {
+        let logger = TracingBuilder::new()
+            .filter("topohedral_mesh", LevelFilter::Trace)
+            .parse_directives("topohedral_mesh=error")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            logger.sinks[0]
+                .filter
+                .load()
+                .target_level("topohedral_mesh"),
+            Some(LevelFilter::Error)
+        );
+    }
+
+    #[test]
+    fn test_builder_strict() {
+        let err = TracingBuilder::new()
+            .parse_directives("topohedral_mesh=dbug")
+            .strict(true)
+            .build()
+            .err()
+            .unwrap();
+
+        match err {
+            InitError::Filter(err) => assert_eq!(err.directive(), "topohedral_mesh=dbug"),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+}
+//}}}
@@ -0,0 +1,174 @@
+//! The source location a record is logged from, and the rate limits of records logged there.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+//}}}
+//{{{ std imports
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+//}}}
+//{{{ dep imports
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Callsite
+//{{{ struct: Callsite
+/// Where in the source a record is logged from.
+///
+/// The logging macros declare one of these as a `static` at every call site, capturing
+/// `module_path!()`, `file!()`, `line!()` and `column!()` separately. The file, module and line
+/// end up in the `log::Record`, which has no column, so the column is handed to the sinks of this
+/// crate alongside it.
+///
+/// A call site also keeps the state of the rate limits the macros apply with `every = N;`,
+/// `once;` and `per_second = N;`, see [`Callsite::every`], [`Callsite::once`] and
+/// [`Callsite::per_second`].
+///
+/// ```
+/// use topohedral_tracing::{topo_enabled, topo_log, Callsite, Level};
+///
+/// static CALLSITE: Callsite =
+///     Callsite::new(module_path!(), file!(), line!(), column!());
+/// if topo_enabled(module_path!(), Level::Info) {
+///     topo_log(module_path!(), Level::Info, &CALLSITE, format_args!("converged"));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Callsite {
+    module: &'static str,
+    file: &'static str,
+    line: u32,
+    column: u32,
+    /// The number of times a rate limit was asked whether to log a record.
+    hits: AtomicU64,
+    /// The token bucket of `per_second`, filled on first use.
+    bucket: Mutex<Option<Bucket>>,
+}
+//}}}
+//{{{ struct: Bucket
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+//}}}
+//{{{ impl Callsite
+impl Callsite {
+    /// Creates a call site from the module path, file, line and column.
+    pub const fn new(module: &'static str, file: &'static str, line: u32, column: u32) -> Self {
+        Self {
+            module,
+            file,
+            line,
+            column,
+            hits: AtomicU64::new(0),
+            bucket: Mutex::new(None),
+        }
+    }
+
+    /// The module path, as given by `module_path!()`.
+    pub fn module(&self) -> &'static str {
+        self.module
+    }
+
+    /// The path of the source file, as given by `file!()`.
+    pub fn file(&self) -> &'static str {
+        self.file
+    }
+
+    /// The line in the source file, starting at 1.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// The column in the line, starting at 1.
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+
+    /// Whether to log a record limited to one in every `n`, which is true for the first record
+    /// and every `n`th after it. An `n` of 0 is taken as 1.
+    pub fn every(&self, n: u64) -> bool {
+        self.hits.fetch_add(1, Ordering::Relaxed) % n.max(1) == 0
+    }
+
+    /// Whether to log a record limited to once, which is true for the first record only.
+    pub fn once(&self) -> bool {
+        self.hits.swap(1, Ordering::Relaxed) == 0
+    }
+
+    /// Whether to log a record limited to `rate` per second.
+    ///
+    /// This is a token bucket holding up to `rate` tokens, which starts full and refills at `rate`
+    /// tokens per second. A record is logged if it can take a token, so bursts of up to `rate`
+    /// records get through at once, after which records are logged at the steady rate.
+    pub fn per_second(&self, rate: u32) -> bool {
+        let rate = f64::from(rate);
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: rate,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+//}}}
+//{{{ impl fmt::Display for Callsite
+/// Shows the call site as `path:line:column`, which terminals and editors turn into a link.
+impl fmt::Display for Callsite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+//}}}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn new_callsite() -> Callsite {
+        Callsite::new(module_path!(), file!(), line!(), column!())
+    }
+
+    #[test]
+    fn test_every() {
+        let callsite = new_callsite();
+        let logged: Vec<usize> = (0..10).filter(|_| callsite.every(4)).collect();
+        assert_eq!(logged, [0, 4, 8]);
+
+        let callsite = new_callsite();
+        assert!((0..3).all(|_| callsite.every(0)));
+    }
+
+    #[test]
+    fn test_once() {
+        let callsite = new_callsite();
+        let logged = (0..10).filter(|_| callsite.once()).count();
+        assert_eq!(logged, 1);
+    }
+
+    #[test]
+    fn test_per_second() {
+        let callsite = new_callsite();
+        let logged = (0..100).filter(|_| callsite.per_second(5)).count();
+        assert_eq!(logged, 5);
+
+        let callsite = new_callsite();
+        assert!(!callsite.per_second(0));
+    }
+}
+//}}}
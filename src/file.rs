@@ -0,0 +1,417 @@
+//! Writing records to a file which is rotated by size or by time.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+//}}}
+//{{{ std imports
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+//}}}
+//{{{ dep imports
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, TimeZone, Timelike};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Rotation
+//{{{ enum: Rotation
+/// When the log file is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    /// The file grows without bound, the default.
+    #[default]
+    Never,
+    /// Rotate before the file would grow beyond the given number of bytes.
+    Size(u64),
+    /// Rotate at the start of every hour, local time.
+    Hourly,
+    /// Rotate at midnight, local time.
+    Daily,
+}
+//}}}
+//{{{ impl Rotation
+impl Rotation {
+    /// Parses the value of `TOPO_LOG_ROTATE`: `never`, `hourly`, `daily` or a size such as
+    /// `10MB`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            size => parse_size(size).map(Rotation::Size).ok_or_else(|| {
+                "expected `never`, `hourly`, `daily` or a size such as `10MB`".to_string()
+            }),
+        }
+    }
+
+    /// The start of the next period after `now`, or `None` if not rotating by time.
+    fn next_boundary(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = now.naive_local().with_second(0)?.with_nanosecond(0)?;
+        let next = match self {
+            Rotation::Hourly => start.with_minute(0)? + TimeDelta::hours(1),
+            Rotation::Daily => start.date().and_hms_opt(0, 0, 0)? + TimeDelta::days(1),
+            Rotation::Never | Rotation::Size(_) => return None,
+        };
+        local_time(next)
+    }
+}
+//}}}
+//}}}
+//{{{ collection: FileTarget
+//{{{ struct: FileTarget
+/// Settings for writing records to a file.
+///
+/// Rotated files are named after the log file with a number appended, `trace.log.1` being the
+/// most recent, and the oldest are deleted so that at most [`FileTarget::keep`] remain. With
+/// compression enabled the rotated files are gzipped to `trace.log.1.gz` and so on.
+///
+/// ```no_run
+/// use topohedral_tracing::{FileTarget, Rotation, Target, TracingBuilder};
+///
+/// let file = FileTarget::new("trace.log")
+///     .rotation(Rotation::Size(100 * 1024 * 1024))
+///     .keep(3)
+///     .compress(true);
+/// TracingBuilder::from_env()
+///     .writer(Target::File(file))
+///     .init()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTarget {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    compress: bool,
+}
+//}}}
+//{{{ impl FileTarget
+impl FileTarget {
+    /// Writes to the file at `path`, appending if it exists, without rotation.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            rotation: Rotation::Never,
+            keep: 5,
+            compress: false,
+        }
+    }
+
+    /// Sets when the file is rotated.
+    pub fn rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Sets how many rotated files are kept, 5 by default.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Sets whether rotated files are gzipped, which happens on a helper thread after rotating.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+}
+//}}}
+//}}}
+//{{{ collection: RotatingFile
+//{{{ struct: RotatingFile
+/// An open log file together with the state needed to rotate it.
+pub(crate) struct RotatingFile {
+    target: FileTarget,
+    /// The current file, written out at the end of every line so that no record is lost if the
+    /// logger, which lives in a static, is never flushed.
+    file: LineWriter<File>,
+    /// The number of bytes in the current file.
+    size: u64,
+    /// When the current period ends, for rotation by time.
+    next_boundary: Option<DateTime<Local>>,
+    /// The thread compressing the most recently rotated file, if any.
+    compressing: Option<JoinHandle<()>>,
+}
+//}}}
+//{{{ impl RotatingFile
+impl RotatingFile {
+    /// Opens the log file, creating it if needed.
+    pub(crate) fn open(target: FileTarget) -> io::Result<Self> {
+        let file = open_append(&target.path)?;
+        let size = file.metadata()?.len();
+        let next_boundary = target.rotation.next_boundary(Local::now());
+
+        Ok(Self {
+            target,
+            file: LineWriter::new(file),
+            size,
+            next_boundary,
+            compressing: None,
+        })
+    }
+
+    /// Writes a single line, rotating the file first if it is due.
+    pub(crate) fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.rotation_due(len) {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Flushes any partly written line to the file, first waiting for the rotated file being
+    /// compressed, if any.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.wait_compressed();
+        self.file.flush()
+    }
+
+    /// Waits for the thread compressing the last rotated file to finish.
+    fn wait_compressed(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+    }
+
+    /// Whether the file has to be rotated before writing `len` more bytes.
+    fn rotation_due(&self, len: u64) -> bool {
+        match self.target.rotation {
+            Rotation::Never => false,
+            Rotation::Size(limit) => self.size > 0 && self.size + len > limit,
+            Rotation::Hourly | Rotation::Daily => self
+                .next_boundary
+                .is_some_and(|boundary| Local::now() >= boundary),
+        }
+    }
+
+    /// Moves the current file to `<path>.1`, shifting older files up and deleting those beyond
+    /// the retention count, then starts a new file.
+    ///
+    /// With compression the file is only renamed to `<path>.1` here, and gzipped to
+    /// `<path>.1.gz` by a helper thread, so that the threads logging to the file are not held up
+    /// while it is compressed. The next rotation waits for that thread before shifting the files.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.wait_compressed();
+
+        let extension = if self.target.compress { ".gz" } else { "" };
+        let rotated = |n: usize| numbered_path(&self.target.path, n, extension);
+
+        for n in (1..=self.target.keep).rev() {
+            let from = rotated(n);
+            if !from.exists() {
+                continue;
+            }
+            if n == self.target.keep {
+                fs::remove_file(&from)?;
+            } else {
+                fs::rename(&from, rotated(n + 1))?;
+            }
+        }
+
+        if self.target.keep == 0 {
+            fs::remove_file(&self.target.path)?;
+        } else if self.target.compress {
+            let from = numbered_path(&self.target.path, 1, "");
+            let to = rotated(1);
+            fs::rename(&self.target.path, &from)?;
+            let compress = {
+                let (from, to) = (from.clone(), to.clone());
+                move || {
+                    if gzip(&from, &to).is_ok() {
+                        let _ = fs::remove_file(&from);
+                    }
+                }
+            };
+            match thread::Builder::new()
+                .name("topohedral-tracing-gzip".to_string())
+                .spawn(compress)
+            {
+                Ok(compressing) => self.compressing = Some(compressing),
+                Err(_) => {
+                    gzip(&from, &to)?;
+                    fs::remove_file(&from)?;
+                }
+            }
+        } else {
+            fs::rename(&self.target.path, rotated(1))?;
+        }
+
+        self.file = LineWriter::new(open_append(&self.target.path)?);
+        self.size = 0;
+        self.next_boundary = self.target.rotation.next_boundary(Local::now());
+        Ok(())
+    }
+}
+//}}}
+//}}}
+//{{{ fun: open_append
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+//}}}
+//{{{ fun: numbered_path
+/// The path of the `n`th rotated file, e.g. `trace.log.2.gz`.
+fn numbered_path(path: &Path, n: usize, extension: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}{}", n, extension));
+    PathBuf::from(name)
+}
+//}}}
+//{{{ fun: gzip
+/// Writes a gzipped copy of the file at `from` to `to`.
+fn gzip(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(to)?), Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()
+}
+//}}}
+//{{{ fun: local_time
+/// Converts a naive local time to the local time zone, taking the earlier time if it is ambiguous
+/// and skipping forward if it falls in a gap.
+fn local_time(time: NaiveDateTime) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&time).earliest().or_else(|| {
+        Local
+            .from_local_datetime(&(time + TimeDelta::hours(1)))
+            .earliest()
+    })
+}
+//}}}
+//{{{ fun: parse_size
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix, optionally followed by `B`,
+/// using binary multiples.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let upper = size.to_ascii_uppercase();
+    let number = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, multiplier) = match number.chars().last()? {
+        'K' => (&number[..number.len() - 1], 1 << 10),
+        'M' => (&number[..number.len() - 1], 1 << 20),
+        'G' => (&number[..number.len() - 1], 1 << 30),
+        _ => (number, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "topohedral-tracing-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_rotation() {
+        assert_eq!(Rotation::parse("daily"), Ok(Rotation::Daily));
+        assert_eq!(Rotation::parse("10MB"), Ok(Rotation::Size(10 << 20)));
+        assert_eq!(Rotation::parse("512k"), Ok(Rotation::Size(512 << 10)));
+        assert_eq!(Rotation::parse("4096"), Ok(Rotation::Size(4096)));
+        assert!(Rotation::parse("weekly").is_err());
+        assert!(Rotation::parse("MB").is_err());
+    }
+
+    #[test]
+    fn test_next_boundary() {
+        let now = Local.with_ymd_and_hms(2024, 10, 10, 14, 3, 21).unwrap();
+        assert_eq!(
+            Rotation::Hourly.next_boundary(now),
+            Some(Local.with_ymd_and_hms(2024, 10, 10, 15, 0, 0).unwrap())
+        );
+        assert_eq!(
+            Rotation::Daily.next_boundary(now),
+            Some(Local.with_ymd_and_hms(2024, 10, 11, 0, 0, 0).unwrap())
+        );
+        assert_eq!(Rotation::Size(10).next_boundary(now), None);
+    }
+
+    #[test]
+    fn test_write_unflushed() {
+        let dir = temp_dir("unflushed");
+        let path = dir.join("trace.log");
+        let mut file = RotatingFile::open(FileTarget::new(&path)).unwrap();
+
+        file.write_line("converged").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "converged\n");
+
+        drop(file);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = temp_dir("size");
+        let path = dir.join("trace.log");
+        let target = FileTarget::new(&path).rotation(Rotation::Size(10)).keep(2);
+        let mut file = RotatingFile::open(target).unwrap();
+
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("trace.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("trace.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("trace.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_compressed() {
+        let dir = temp_dir("gzip");
+        let path = dir.join("trace.log");
+        let target = FileTarget::new(&path)
+            .rotation(Rotation::Size(10))
+            .compress(true);
+        let mut file = RotatingFile::open(target).unwrap();
+
+        for line in ["first", "second", "third"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+
+        let gunzip = |name: &str| {
+            let mut contents = String::new();
+            GzDecoder::new(File::open(dir.join(name)).unwrap())
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        assert_eq!(gunzip("trace.log.1.gz"), "second\n");
+        assert_eq!(gunzip("trace.log.2.gz"), "first\n");
+        assert!(!dir.join("trace.log.1").exists());
+        assert!(!dir.join("trace.log.2").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+//}}}
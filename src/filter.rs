@@ -0,0 +1,640 @@
+//! Runtime filters parsed from the `TOPO_LOG` directive syntax.
+//!
+//! A filter is a set of `<target>=<level>` directives plus the level of the special `all` target,
+//! which applies to any target no directive covers. A directive can end in `@<rate>` to keep only
+//! a fraction of the records at its level.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+//}}}
+//{{{ std imports
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+//}}}
+//{{{ dep imports
+use log::{LevelFilter, Metadata};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: FilterParseError
+//{{{ enum: FilterParseErrorKind
+/// The ways in which a filter directive can be malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterParseErrorKind {
+    /// The level after the `=` is not one of the known level names or numbers.
+    UnknownLevel(String),
+    /// The directive contains more than one `=`.
+    TooManyEquals,
+    /// The directive is empty, e.g. a doubled or trailing `,`.
+    EmptyDirective,
+    /// The directive has a level but no target, e.g. `=debug`.
+    EmptyTarget,
+    /// The sampling rate after the `@` is not a fraction between 0 and 1, such as `0.01` or
+    /// `1/100`.
+    InvalidSampleRate(String),
+    /// The directives are not valid Unicode.
+    NotUnicode,
+}
+//}}}
+//{{{ struct: FilterParseError
+/// Error returned when a filter directive cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterParseError {
+    directive: String,
+    column: usize,
+    kind: FilterParseErrorKind,
+}
+//}}}
+//{{{ impl FilterParseError
+impl FilterParseError {
+    fn new(directive: &str, column: usize, kind: FilterParseErrorKind) -> Self {
+        Self {
+            directive: directive.to_string(),
+            column,
+            kind,
+        }
+    }
+
+    /// The directive the error was found in.
+    pub fn directive(&self) -> &str {
+        &self.directive
+    }
+
+    /// The 1-based column of the error, counted in characters from the start of the full filter
+    /// string.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// What is wrong with the directive.
+    pub fn kind(&self) -> &FilterParseErrorKind {
+        &self.kind
+    }
+}
+//}}}
+//{{{ impl fmt::Display for FilterParseError
+impl fmt::Display for FilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid directive `{}` at column {}: ",
+            self.directive, self.column
+        )?;
+        match &self.kind {
+            FilterParseErrorKind::UnknownLevel(level) => write!(f, "unknown level `{}`", level),
+            FilterParseErrorKind::TooManyEquals => f.write_str("more than one `=`"),
+            FilterParseErrorKind::EmptyDirective => f.write_str("empty directive"),
+            FilterParseErrorKind::EmptyTarget => f.write_str("empty target"),
+            FilterParseErrorKind::InvalidSampleRate(rate) => {
+                write!(f, "invalid sampling rate `{}`", rate)
+            }
+            FilterParseErrorKind::NotUnicode => f.write_str("not valid unicode"),
+        }
+    }
+}
+//}}}
+//{{{ impl std::error::Error for FilterParseError
+impl std::error::Error for FilterParseError {}
+//}}}
+//}}}
+//{{{ collection: Filter
+//{{{ struct: Filter
+/// A parsed set of filter directives.
+#[derive(Debug, Clone)]
+pub(crate) struct Filter {
+    /// The directive for `all`, if any, which applies to targets no other directive covers.
+    all: Option<Directive>,
+    targets: HashMap<String, Directive>,
+}
+//}}}
+//{{{ struct: Directive
+/// The level of a target, and the fraction of the records at that level kept.
+#[derive(Debug, Clone)]
+struct Directive {
+    level: LevelFilter,
+    /// Shared between the clones of the filter, so that they keep counting together.
+    sample: Option<Arc<Sample>>,
+}
+//}}}
+//{{{ struct: Sample
+/// The sampling rate of a directive, keeping `kept` in every `of` records.
+#[derive(Debug)]
+struct Sample {
+    kept: u64,
+    of: u64,
+    /// The number of records the directive has been asked about.
+    count: AtomicU64,
+}
+//}}}
+//{{{ impl Sample
+impl Sample {
+    /// Whether to keep the next record.
+    ///
+    /// The records kept are spread evenly, `kept` in every run of `of` consecutive records, and
+    /// which ones only depends on their position in the sequence and `seed`. So the same records
+    /// are kept by every run logging them in the same order with the same seed.
+    fn keep(&self, seed: u64) -> bool {
+        let n = self
+            .count
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(seed)
+            % self.of;
+        u128::from(n) * u128::from(self.kept) % u128::from(self.of) < u128::from(self.kept)
+    }
+}
+//}}}
+//{{{ impl Filter
+impl Filter {
+    /// Creates a filter which lets nothing through.
+    pub(crate) fn new() -> Self {
+        Self {
+            all: None,
+            targets: HashMap::new(),
+        }
+    }
+
+    /// Parses a comma separated list of directives, failing on the first malformed one.
+    pub(crate) fn parse(spec: &str) -> Result<Self, FilterParseError> {
+        let mut filter = Self::new();
+        let mut errors = filter.parse_directives(spec);
+        if errors.is_empty() {
+            Ok(filter)
+        } else {
+            Err(errors.swap_remove(0))
+        }
+    }
+
+    /// Adds the directives from a comma separated list, skipping malformed ones.
+    ///
+    /// Returns an error for every skipped directive.
+    pub(crate) fn parse_directives(&mut self, spec: &str) -> Vec<FilterParseError> {
+        let mut errors = Vec::new();
+
+        if spec.trim().is_empty() {
+            return errors;
+        }
+
+        let mut column = 1;
+        for raw in spec.split(',') {
+            let leading = raw.chars().take_while(|c| c.is_whitespace()).count();
+            let directive = raw.trim();
+            let start = column + leading;
+            column += raw.chars().count() + 1;
+
+            match parse_directive(directive, start) {
+                Ok((target, directive)) => self.insert_directive(target, directive),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        errors
+    }
+
+    /// Adds the directives from a value which may not be valid Unicode, such as an environment
+    /// variable or a file.
+    ///
+    /// Returns an error for every skipped directive.
+    pub(crate) fn parse_os(&mut self, spec: &OsStr) -> Vec<FilterParseError> {
+        match spec.to_str() {
+            Some(spec) => self.parse_directives(spec),
+            None => {
+                let spec = spec.to_string_lossy();
+                let column = spec
+                    .chars()
+                    .position(|c| c == char::REPLACEMENT_CHARACTER)
+                    .unwrap_or(0)
+                    + 1;
+                vec![FilterParseError::new(
+                    &spec,
+                    column,
+                    FilterParseErrorKind::NotUnicode,
+                )]
+            }
+        }
+    }
+
+    /// Builds a filter by applying the sources in order.
+    ///
+    /// Besides the filter and the errors for any skipped directives, this returns the text read
+    /// from the environment and from files, so a later rebuild can tell whether anything changed.
+    /// A missing file is treated as empty, any other failure to read a file, including contents
+    /// which are not valid UTF-8, is an error.
+    pub(crate) fn from_sources(sources: &[FilterSource]) -> io::Result<FilterBuild> {
+        let mut build = FilterBuild {
+            filter: Self::new(),
+            errors: Vec::new(),
+            inputs: Vec::new(),
+        };
+
+        for source in sources {
+            match source {
+                FilterSource::Level(target, level) => build.filter.insert(target, *level),
+                FilterSource::Directives(spec) => {
+                    let errors = build.filter.parse_directives(spec);
+                    build.errors.extend(errors);
+                }
+                FilterSource::Env(var) => {
+                    let input = std::env::var_os(var);
+                    if let Some(spec) = &input {
+                        let errors = build.filter.parse_os(spec);
+                        build.errors.extend(errors);
+                    }
+                    build.inputs.push(input);
+                }
+                FilterSource::File(path) => {
+                    let input = match fs::read_to_string(path) {
+                        Ok(contents) => Some(contents),
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                        Err(err) => return Err(err),
+                    };
+                    if let Some(contents) = &input {
+                        let errors = build.filter.parse_directives(&file_to_directives(contents));
+                        build.errors.extend(errors);
+                    }
+                    build.inputs.push(input.map(OsString::from));
+                }
+            }
+        }
+
+        Ok(build)
+    }
+
+    /// Sets the level of `target`, where the target `all` sets the default level.
+    pub(crate) fn insert(&mut self, target: &str, level: LevelFilter) {
+        self.insert_directive(
+            target,
+            Directive {
+                level,
+                sample: None,
+            },
+        );
+    }
+
+    fn insert_directive(&mut self, target: &str, directive: Directive) {
+        if target == "all" {
+            self.all = Some(directive);
+        } else {
+            self.targets.insert(target.to_string(), directive);
+        }
+    }
+
+    /// Finds the most specific directive covering `target`, other than `all`.
+    ///
+    /// Directives match on whole `::` separated path segments, so a directive for `a::b` covers
+    /// `a::b` and `a::b::c` but not `a::bc`. The longest matching directive wins.
+    fn target_directive(&self, target: &str) -> Option<&Directive> {
+        let mut prefix = target;
+        loop {
+            if let Some(directive) = self.targets.get(prefix) {
+                return Some(directive);
+            }
+            match prefix.rfind("::") {
+                Some(idx) => prefix = &prefix[..idx],
+                None => return None,
+            }
+        }
+    }
+
+    /// Finds the level of the most specific directive covering `target`, see
+    /// [`Filter::target_directive`].
+    pub(crate) fn target_level(&self, target: &str) -> Option<LevelFilter> {
+        self.target_directive(target)
+            .map(|directive| directive.level)
+    }
+
+    /// The level `target` is logged at, or `None` if no directive covers it, not even `all`.
+    pub(crate) fn level(&self, target: &str) -> Option<LevelFilter> {
+        self.target_level(target)
+            .or_else(|| self.all.as_ref().map(|directive| directive.level))
+    }
+
+    /// Whether a record with the given metadata passes the filter.
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> bool {
+        let target_level = self.level(metadata.target()).unwrap_or(LevelFilter::Off);
+
+        metadata.level() <= target_level
+    }
+
+    /// Whether to keep a record this filter enables, given the seed of the sampling.
+    ///
+    /// Only records at the level of a sampled directive are sampled, each directive counting the
+    /// records it covers on its own. Records at less verbose levels are always kept.
+    pub(crate) fn sampled(&self, metadata: &Metadata, seed: u64) -> bool {
+        match self
+            .target_directive(metadata.target())
+            .or(self.all.as_ref())
+        {
+            Some(Directive {
+                level,
+                sample: Some(sample),
+            }) if metadata.level() == *level => sample.keep(seed),
+            _ => true,
+        }
+    }
+
+    /// The most verbose level any target can be logged at.
+    pub(crate) fn max_level(&self) -> LevelFilter {
+        let all = self
+            .all
+            .as_ref()
+            .map_or(LevelFilter::Off, |directive| directive.level);
+        self.targets
+            .values()
+            .map(|directive| directive.level)
+            .fold(all, std::cmp::max)
+    }
+}
+//}}}
+//{{{ enum: FilterSource
+/// One of the inputs a filter is built from, kept so that the filter can be rebuilt on reload.
+#[derive(Debug, Clone)]
+pub(crate) enum FilterSource {
+    /// A single target and level given in code.
+    Level(String, LevelFilter),
+    /// Directives given in code.
+    Directives(String),
+    /// Directives read from an environment variable.
+    Env(String),
+    /// Directives read from a file, one or more per line, with `#` starting a comment.
+    File(PathBuf),
+}
+//}}}
+//{{{ struct: FilterBuild
+/// The result of building a filter from its sources.
+#[derive(Debug)]
+pub(crate) struct FilterBuild {
+    pub(crate) filter: Filter,
+    pub(crate) errors: Vec<FilterParseError>,
+    /// The values read from the environment and from files, `None` where missing.
+    pub(crate) inputs: Vec<Option<OsString>>,
+}
+//}}}
+//{{{ impl Default for Filter
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+//}}}
+//{{{ fun: parse_directive
+/// Parses a single trimmed directive which starts at `column` of the full filter string.
+fn parse_directive(directive: &str, column: usize) -> Result<(&str, Directive), FilterParseError> {
+    let error = |offset: usize, kind| FilterParseError::new(directive, column + offset, kind);
+
+    if directive.is_empty() {
+        return Err(error(0, FilterParseErrorKind::EmptyDirective));
+    }
+
+    let (body, rate) = match directive.split_once('@') {
+        Some((body, rate)) => (body, Some(rate)),
+        None => (directive, None),
+    };
+    let sample = match rate {
+        None => None,
+        Some(rate) => match parse_rate(rate) {
+            Some((kept, of)) => Some(Arc::new(Sample {
+                kept,
+                of,
+                count: AtomicU64::new(0),
+            })),
+            None => {
+                let offset = body.chars().count() + 1;
+                let kind = FilterParseErrorKind::InvalidSampleRate(rate.to_string());
+                return Err(error(offset, kind));
+            }
+        },
+    };
+
+    let mut pieces = body.split('=');
+    let target = pieces.next().unwrap_or_default();
+    let level = pieces.next();
+    if pieces.next().is_some() {
+        let second = body.match_indices('=').nth(1).map_or(0, |(idx, _)| idx);
+        let offset = body[..second].chars().count();
+        return Err(error(offset, FilterParseErrorKind::TooManyEquals));
+    }
+    if target.is_empty() {
+        return Err(error(0, FilterParseErrorKind::EmptyTarget));
+    }
+
+    let level = match level {
+        None => LevelFilter::Info,
+        Some(level) => match parse_level(level) {
+            Some(level) => level,
+            None => {
+                let offset = target.chars().count() + 1;
+                let kind = FilterParseErrorKind::UnknownLevel(level.to_string());
+                return Err(error(offset, kind));
+            }
+        },
+    };
+
+    Ok((target, Directive { level, sample }))
+}
+//}}}
+//{{{ fun: parse_rate
+/// Parses a sampling rate, a decimal such as `0.01` or a ratio such as `1/100`, into the number
+/// of records kept in every so many. The rate must be above 0 and at most 1, and is written with
+/// digits only, without a sign.
+fn parse_rate(rate: &str) -> Option<(u64, u64)> {
+    if !rate
+        .bytes()
+        .all(|b| b.is_ascii_digit() || b == b'.' || b == b'/')
+    {
+        return None;
+    }
+    let (kept, of) = match rate.split_once('/') {
+        Some((kept, of)) => (kept.parse().ok()?, of.parse().ok()?),
+        None => {
+            let (whole, fraction) = rate.split_once('.').unwrap_or((rate, ""));
+            if fraction.contains('.') || fraction.len() > 18 {
+                return None;
+            }
+            let kept = format!("{}{}", whole, fraction).parse().ok()?;
+            (kept, 10u64.pow(fraction.len() as u32))
+        }
+    };
+    (kept > 0 && kept <= of).then_some((kept, of))
+}
+//}}}
+//{{{ fun: file_to_directives
+/// Turns the contents of a filter file into a single comma separated list of directives.
+fn file_to_directives(contents: &str) -> String {
+    let directives: Vec<&str> = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+    directives.join(",")
+}
+//}}}
+//{{{ fun: parse_level
+/// Parses a level name or number as used in the directives.
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level {
+        "trace" | "5" => Some(LevelFilter::Trace),
+        "debug" | "4" => Some(LevelFilter::Debug),
+        "info" | "3" => Some(LevelFilter::Info),
+        "warn" | "2" => Some(LevelFilter::Warn),
+        "error" | "1" => Some(LevelFilter::Error),
+        "off" | "0" => Some(LevelFilter::Off),
+        _ => None,
+    }
+}
+//}}}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use log::Level;
+
+    fn enabled(filter: &Filter, target: &str, level: Level) -> bool {
+        filter.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn test_target_prefix_matching() {
+        let filter =
+            Filter::parse("topohedral_linalg=info,topohedral_linalg::dense=trace").unwrap();
+
+        assert_eq!(
+            filter.target_level("topohedral_linalg"),
+            Some(LevelFilter::Info)
+        );
+        assert_eq!(
+            filter.target_level("topohedral_linalg::sparse::csr"),
+            Some(LevelFilter::Info)
+        );
+        assert_eq!(
+            filter.target_level("topohedral_linalg::dense::matrix"),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(filter.target_level("topohedral_linalg_extra"), None);
+        assert_eq!(
+            filter.target_level("topohedral_linalg::densely"),
+            Some(LevelFilter::Info)
+        );
+        assert_eq!(filter.target_level("topohedral_mesh"), None);
+    }
+
+    #[test]
+    fn test_specific_target_overrides_all() {
+        let filter =
+            Filter::parse("all=debug,topohedral_mesh=warn,topohedral_mesh::kernel=off").unwrap();
+
+        assert!(enabled(&filter, "topohedral_geom", Level::Debug));
+        assert!(!enabled(&filter, "topohedral_mesh::delaunay", Level::Info));
+        assert!(enabled(&filter, "topohedral_mesh::delaunay", Level::Warn));
+        assert!(!enabled(&filter, "topohedral_mesh::kernel", Level::Error));
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = Filter::parse("all=debug,mesh=dbug").unwrap_err();
+        assert_eq!(err.directive(), "mesh=dbug");
+        assert_eq!(err.column(), 16);
+        assert_eq!(
+            err.kind(),
+            &FilterParseErrorKind::UnknownLevel("dbug".into())
+        );
+
+        let err = Filter::parse("mesh=debug=trace").unwrap_err();
+        assert_eq!(err.column(), 11);
+        assert_eq!(err.kind(), &FilterParseErrorKind::TooManyEquals);
+
+        let err = Filter::parse("mesh=debug,,geom").unwrap_err();
+        assert_eq!(err.column(), 12);
+        assert_eq!(err.kind(), &FilterParseErrorKind::EmptyDirective);
+
+        let err = Filter::parse("mesh, =warn").unwrap_err();
+        assert_eq!(err.column(), 7);
+        assert_eq!(err.kind(), &FilterParseErrorKind::EmptyTarget);
+
+        let err = Filter::parse("mesh=info@+0.5").unwrap_err();
+        assert_eq!(err.column(), 11);
+        assert_eq!(
+            err.kind(),
+            &FilterParseErrorKind::InvalidSampleRate("+0.5".into())
+        );
+    }
+
+    #[test]
+    fn test_parse_sample_rates() {
+        assert_eq!(parse_rate("0.01"), Some((1, 100)));
+        assert_eq!(parse_rate("1/100"), Some((1, 100)));
+        assert_eq!(parse_rate(".25"), Some((25, 100)));
+        assert_eq!(parse_rate("1"), Some((1, 1)));
+        assert_eq!(parse_rate("0"), None);
+        assert_eq!(parse_rate("1.5"), None);
+        assert_eq!(parse_rate("3/2"), None);
+        assert_eq!(parse_rate("1/0"), None);
+        assert_eq!(parse_rate("1%"), None);
+        assert_eq!(parse_rate("+0.5"), None);
+        assert_eq!(parse_rate("+1/2"), None);
+        assert_eq!(parse_rate("-0"), None);
+
+        let filter = Filter::parse("topohedral_linalg=trace@0.01").unwrap();
+        assert_eq!(
+            filter.target_level("topohedral_linalg::dense"),
+            Some(LevelFilter::Trace)
+        );
+
+        let err = Filter::parse("all=warn,mesh=trace@2").unwrap_err();
+        assert_eq!(err.directive(), "mesh=trace@2");
+        assert_eq!(err.column(), 21);
+        assert_eq!(
+            err.kind(),
+            &FilterParseErrorKind::InvalidSampleRate("2".into())
+        );
+    }
+
+    #[test]
+    fn test_sampling() {
+        let kept = |filter: &Filter, level: Level, seed: u64| -> Vec<usize> {
+            let metadata = Metadata::builder()
+                .target("topohedral_linalg::dense")
+                .level(level)
+                .build();
+            (0..12)
+                .filter(|_| filter.sampled(&metadata, seed))
+                .collect()
+        };
+
+        let filter = Filter::parse("all=info,topohedral_linalg=trace@1/4").unwrap();
+        assert_eq!(kept(&filter, Level::Trace, 0), [0, 4, 8]);
+        assert_eq!(kept(&filter, Level::Debug, 0).len(), 12);
+        let filter = Filter::parse("topohedral_linalg=trace@0.25").unwrap();
+        assert_eq!(kept(&filter, Level::Trace, 1), [3, 7, 11]);
+        let filter = Filter::parse("topohedral_linalg=trace@3/10").unwrap();
+        assert_eq!(kept(&filter, Level::Trace, 0), [0, 4, 7, 10]);
+
+        let filter = Filter::parse("all=debug@1/3,topohedral_linalg::sparse=trace").unwrap();
+        assert_eq!(kept(&filter, Level::Debug, 0), [0, 3, 6, 9]);
+        let metadata = Metadata::builder().level(Level::Debug).build();
+        assert!(filter.sampled(&metadata, 0));
+        let clone = filter.clone();
+        assert_eq!(kept(&clone, Level::Debug, 0), [2, 5, 8, 11]);
+    }
+
+    #[test]
+    fn test_parse_keeps_valid_directives() {
+        let mut filter = Filter::new();
+        let errors = filter.parse_directives("all=warn,mesh=dbug,geom");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(filter.target_level("mesh"), None);
+        assert_eq!(filter.target_level("geom"), Some(LevelFilter::Info));
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+    }
+}
+//}}}
@@ -0,0 +1,972 @@
+//! Formatting of the records written by the logger.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::span;
+//}}}
+//{{{ std imports
+use std::ffi::OsString;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, Thread};
+use std::time::Instant;
+//}}}
+//{{{ dep imports
+use chrono::{Local, SecondsFormat, Utc};
+use colored::Color;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Record};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Timestamp
+//{{{ enum: Timestamp
+/// The time shown at the start of every record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timestamp {
+    /// No timestamp, the default.
+    #[default]
+    None,
+    /// Wall-clock time in UTC as RFC 3339 with microseconds, e.g. `2024-10-10T14:03:21.123456Z`.
+    Utc,
+    /// Wall-clock time in the local time zone as RFC 3339 with microseconds, e.g.
+    /// `2024-10-10T15:03:21.123456+01:00`.
+    Local,
+    /// Monotonic seconds since the logger was installed, e.g. `12.345678`.
+    Uptime,
+}
+//}}}
+//{{{ impl Timestamp
+impl Timestamp {
+    /// Parses the value of `TOPO_LOG_TIME`: `none`, `utc`, `local` or `uptime`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(Timestamp::None),
+            "utc" => Ok(Timestamp::Utc),
+            "local" => Ok(Timestamp::Local),
+            "uptime" => Ok(Timestamp::Uptime),
+            _ => Err("expected one of `none`, `utc`, `local` or `uptime`".to_string()),
+        }
+    }
+
+    /// The current time, or `None` if no timestamp is shown.
+    ///
+    /// `start` is the instant uptime is measured from.
+    pub(crate) fn now(&self, start: Instant) -> Option<TimestampValue> {
+        match self {
+            Timestamp::None => None,
+            Timestamp::Utc => Some(TimestampValue::Wall(
+                Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            )),
+            Timestamp::Local => Some(TimestampValue::Wall(
+                Local::now().to_rfc3339_opts(SecondsFormat::Micros, false),
+            )),
+            Timestamp::Uptime => Some(TimestampValue::Uptime(start.elapsed().as_secs_f64())),
+        }
+    }
+}
+//}}}
+//{{{ enum: TimestampValue
+/// A timestamp taken for a single record.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TimestampValue {
+    /// RFC 3339 wall-clock time.
+    Wall(String),
+    /// Seconds since the logger was installed.
+    Uptime(f64),
+}
+//}}}
+//{{{ impl fmt::Display for TimestampValue
+impl fmt::Display for TimestampValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampValue::Wall(time) => f.write_str(time),
+            TimestampValue::Uptime(secs) => write!(f, "{:.6}", secs),
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ collection: Style
+//{{{ enum: Style
+/// Whether the level of text records is colored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Style {
+    /// Color only when writing to stderr or stdout attached to a terminal, following the `NO_COLOR`
+    /// and `CLICOLOR_FORCE` conventions, the default.
+    #[default]
+    Auto,
+    /// Always color stderr or stdout, even when not attached to a terminal. Files and pipes are
+    /// still not colored.
+    Always,
+    /// Never color.
+    Never,
+}
+//}}}
+//{{{ impl Style
+impl Style {
+    /// Parses the value of `TOPO_LOG_STYLE`: `auto`, `always` or `never`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "auto" => Ok(Style::Auto),
+            "always" => Ok(Style::Always),
+            "never" => Ok(Style::Never),
+            _ => Err("expected one of `auto`, `always` or `never`".to_string()),
+        }
+    }
+
+    /// Whether to color a writer, which is a standard stream if `console` is set and attached to a
+    /// terminal if `terminal` is set.
+    pub(crate) fn colors(self, console: bool, terminal: bool) -> bool {
+        self.colors_with(console, terminal, |name| std::env::var_os(name))
+    }
+
+    /// Like [`Style::colors`], reading the environment through `var`.
+    ///
+    /// With [`Style::Auto`] a non-empty `NO_COLOR` disables color, then `CLICOLOR_FORCE` other
+    /// than `0` enables it even when not writing to a terminal, and `CLICOLOR=0` disables it on a
+    /// terminal. [`Style::Always`] only skips the detection of a terminal, so files and pipes are
+    /// never colored.
+    fn colors_with(
+        self,
+        console: bool,
+        terminal: bool,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> bool {
+        let set = |name: &str| var(name).filter(|value| !value.is_empty());
+        match self {
+            Style::Always => console,
+            Style::Never => false,
+            Style::Auto if !console || set("NO_COLOR").is_some() => false,
+            Style::Auto if set("CLICOLOR_FORCE").is_some_and(|value| value != "0") => true,
+            Style::Auto => terminal && set("CLICOLOR").is_none_or(|value| value != "0"),
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ collection: TemplateError
+//{{{ enum: TemplateErrorKind
+/// The ways in which a format template can be malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateErrorKind {
+    /// The placeholder name is not one of the known fields.
+    UnknownPlaceholder(String),
+    /// A `{` is not closed by a `}`.
+    Unclosed,
+    /// A `}` does not close a placeholder, write `}}` for a literal `}`.
+    Unmatched,
+    /// The text after the `:` of a placeholder is not an optional `<` or `>` followed by a width.
+    InvalidSpec(String),
+}
+//}}}
+//{{{ struct: TemplateError
+/// Error returned when a format template cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    template: String,
+    column: usize,
+    kind: TemplateErrorKind,
+}
+//}}}
+//{{{ impl TemplateError
+impl TemplateError {
+    /// The template the error was found in.
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// The 1-based column of the error, counted in characters.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// What is wrong with the template.
+    pub fn kind(&self) -> &TemplateErrorKind {
+        &self.kind
+    }
+}
+//}}}
+//{{{ impl fmt::Display for TemplateError
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid format template `{}` at column {}: ",
+            self.template, self.column
+        )?;
+        match &self.kind {
+            TemplateErrorKind::UnknownPlaceholder(name) => {
+                write!(f, "unknown placeholder `{{{}}}`", name)
+            }
+            TemplateErrorKind::Unclosed => f.write_str("unclosed `{`"),
+            TemplateErrorKind::Unmatched => f.write_str("unmatched `}`"),
+            TemplateErrorKind::InvalidSpec(spec) => write!(f, "invalid width `{}`", spec),
+        }
+    }
+}
+//}}}
+//{{{ impl std::error::Error for TemplateError
+impl std::error::Error for TemplateError {}
+//}}}
+//}}}
+//{{{ collection: Template
+//{{{ enum: Field
+/// The record fields a template placeholder can refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Time,
+    Level,
+    Thread,
+    ThreadId,
+    ThreadName,
+    Module,
+    File,
+    Line,
+    Column,
+    Location,
+    Target,
+    Msg,
+}
+//}}}
+//{{{ impl Field
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "time" => Some(Field::Time),
+            "level" => Some(Field::Level),
+            "thread" => Some(Field::Thread),
+            "thread_id" => Some(Field::ThreadId),
+            "thread_name" => Some(Field::ThreadName),
+            "module" => Some(Field::Module),
+            "file" => Some(Field::File),
+            "line" => Some(Field::Line),
+            "column" => Some(Field::Column),
+            "location" => Some(Field::Location),
+            "target" => Some(Field::Target),
+            "msg" => Some(Field::Msg),
+            _ => None,
+        }
+    }
+}
+//}}}
+//{{{ enum: Piece
+/// A part of a template, either literal text or a placeholder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Field {
+        field: Field,
+        width: usize,
+        right_align: bool,
+    },
+}
+//}}}
+//{{{ struct: Template
+/// A parsed format template describing the layout of a record.
+///
+/// Placeholders are written `{name}` or `{name:width}`, where the width may be prefixed by `<` or
+/// `>` to align left, the default, or right. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Template {
+    pieces: Vec<Piece>,
+}
+//}}}
+//{{{ impl Template
+impl Template {
+    /// The layout used when no template is given.
+    pub(crate) const DEFAULT: &'static str = "[{level:5} - {thread:3} - {module}:{line}] {msg}";
+
+    /// The layout used when no template is given but timestamps are enabled.
+    pub(crate) const DEFAULT_WITH_TIME: &'static str =
+        "[{time} - {level:5} - {thread:3} - {module}:{line}] {msg}";
+
+    /// The default template for the given timestamp setting.
+    pub(crate) fn default_for(timestamp: Timestamp) -> Self {
+        let template = match timestamp {
+            Timestamp::None => Self::DEFAULT,
+            _ => Self::DEFAULT_WITH_TIME,
+        };
+        Self::parse(template).expect("default templates are valid")
+    }
+
+    /// Parses a template.
+    pub(crate) fn parse(template: &str) -> Result<Self, TemplateError> {
+        let error = |column: usize, kind| TemplateError {
+            template: template.to_string(),
+            column: column + 1,
+            kind,
+        };
+
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().enumerate().peekable();
+
+        while let Some((column, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(error(column, TemplateErrorKind::Unmatched)),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => placeholder.push(c),
+                            None => return Err(error(column, TemplateErrorKind::Unclosed)),
+                        }
+                    }
+
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec)),
+                        None => (placeholder.as_str(), None),
+                    };
+                    let field = Field::parse(name).ok_or_else(|| {
+                        let kind = TemplateErrorKind::UnknownPlaceholder(name.to_string());
+                        error(column, kind)
+                    })?;
+                    let (width, right_align) = match spec {
+                        None => (0, false),
+                        Some(spec) => parse_spec(spec).ok_or_else(|| {
+                            let kind = TemplateErrorKind::InvalidSpec(spec.to_string());
+                            error(column, kind)
+                        })?,
+                    };
+
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(Piece::Field {
+                        field,
+                        width,
+                        right_align,
+                    });
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+
+        Ok(Self { pieces })
+    }
+
+    /// Appends the formatted record to `out`.
+    ///
+    /// `column` is the column the record was logged from, if known, `origin` the thread it was
+    /// logged from and `time` is the timestamp of the record, `{time}` is left empty without one.
+    /// The level is only colored if `color` is set.
+    pub(crate) fn render(
+        &self,
+        record: &Record,
+        column: Option<u32>,
+        origin: &Origin,
+        time: Option<&TimestampValue>,
+        color: bool,
+        out: &mut String,
+    ) {
+        let mut value = String::new();
+        for piece in &self.pieces {
+            let (field, width, right_align) = match piece {
+                Piece::Literal(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Piece::Field {
+                    field,
+                    width,
+                    right_align,
+                } => (*field, *width, *right_align),
+            };
+
+            value.clear();
+            let _ = match field {
+                Field::Time => match time {
+                    Some(time) => write!(value, "{}", time),
+                    None => Ok(()),
+                },
+                Field::Level => write!(value, "{}", record.level()),
+                Field::Thread => match origin.thread.name() {
+                    Some(name) => write!(value, "{}", name),
+                    None => write!(value, "{}", origin.id),
+                },
+                Field::ThreadId => write!(value, "{}", origin.id),
+                Field::ThreadName => {
+                    write!(value, "{}", origin.thread.name().unwrap_or("<unnamed>"))
+                }
+                Field::Module => {
+                    write!(value, "{}", record.module_path().unwrap_or(record.target()))
+                }
+                Field::File => write!(value, "{}", record.file().unwrap_or("<unknown>")),
+                Field::Line => write!(value, "{}", record.line().unwrap_or(0)),
+                Field::Column => write!(value, "{}", column.unwrap_or(0)),
+                Field::Location => write_location(&mut value, record, column),
+                Field::Target => write!(value, "{}", record.target()),
+                Field::Msg => {
+                    for _ in 0..origin.depth {
+                        value.push_str("  ");
+                    }
+                    let _ = write!(value, "{}", record.args());
+                    record
+                        .key_values()
+                        .visit(&mut TextFields(&mut value))
+                        .map_err(|_| fmt::Error)
+                }
+            };
+
+            let padded = if right_align {
+                format!("{:>width$}", value, width = width)
+            } else {
+                format!("{:<width$}", value, width = width)
+            };
+            match field {
+                Field::Level if color => {
+                    let code = level_color(record.level()).to_fg_str();
+                    let _ = write!(out, "\x1b[{}m{}\x1b[0m", code, padded);
+                }
+                _ => out.push_str(&padded),
+            }
+        }
+    }
+}
+//}}}
+//{{{ struct: TextFields
+/// Visitor appending the key-values of a record as ` key=value` pairs.
+struct TextFields<'a>(&'a mut String);
+//}}}
+//{{{ impl VisitSource for TextFields
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let quote = value.to_borrowed_str().is_some_and(|value| {
+            value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+        });
+        let float = value
+            .to_f64()
+            .filter(|_| value.to_u64().is_none() && value.to_i64().is_none());
+        if quote {
+            write!(self.0, " {}={:?}", key, value.to_string())?;
+        } else if let Some(float) = float {
+            write!(self.0, " {}={:?}", key, float)?;
+        } else {
+            write!(self.0, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+//}}}
+//{{{ fun: write_location
+/// Writes where the record was logged from as `path:line:column`, which terminals and editors turn
+/// into a link, leaving out what is not known.
+fn write_location(out: &mut String, record: &Record, column: Option<u32>) -> fmt::Result {
+    write!(out, "{}", record.file().unwrap_or("<unknown>"))?;
+    if let Some(line) = record.line() {
+        write!(out, ":{}", line)?;
+        if let Some(column) = column {
+            write!(out, ":{}", column)?;
+        }
+    }
+    Ok(())
+}
+//}}}
+//{{{ fun: parse_spec
+/// Parses the part of a placeholder after the `:`, returning the width and whether to align right.
+fn parse_spec(spec: &str) -> Option<(usize, bool)> {
+    let (width, right_align) = match spec.strip_prefix('>') {
+        Some(width) => (width, true),
+        None => (spec.strip_prefix('<').unwrap_or(spec), false),
+    };
+    width.parse().ok().map(|width| (width, right_align))
+}
+//}}}
+//{{{ fun: level_color
+/// The color the level of a record is shown in.
+fn level_color(level: Level) -> Color {
+    match level {
+        Level::Error => Color::Red,
+        Level::Warn => Color::Yellow,
+        Level::Info => Color::Green,
+        Level::Debug => Color::Blue,
+        Level::Trace => Color::Magenta,
+    }
+}
+//}}}
+//}}}
+//{{{ collection: Format
+//{{{ enum: Format
+/// How records are turned into lines of output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// Human readable text laid out by a template.
+    Text(Template),
+    /// One JSON object per line.
+    Json,
+}
+//}}}
+//{{{ impl Format
+impl Format {
+    /// Parses the value of `TOPO_LOG_FORMAT`, which is either `json` or a template.
+    pub(crate) fn parse(value: &str) -> Result<Self, TemplateError> {
+        match value {
+            "json" => Ok(Format::Json),
+            template => Template::parse(template).map(Format::Text),
+        }
+    }
+
+    /// Appends the formatted record to `out`, without a trailing newline.
+    ///
+    /// Text is only colored if `color` is set, JSON never is.
+    pub(crate) fn render(
+        &self,
+        record: &Record,
+        column: Option<u32>,
+        origin: &Origin,
+        time: Option<&TimestampValue>,
+        color: bool,
+        out: &mut String,
+    ) {
+        match self {
+            Format::Text(template) => template.render(record, column, origin, time, color, out),
+            Format::Json => render_json(record, column, origin, time, out),
+        }
+    }
+}
+//}}}
+//{{{ fun: render_json
+/// Appends the record to `out` as a single line JSON object.
+///
+/// The object has the fields `time`, when a timestamp is enabled, `level`, `target`, `module`,
+/// `file`, `line`, `column`, `thread`, `thread_name` and `msg`. Any key-values of the record are
+/// nested in a `fields` object, with numbers and booleans kept as such.
+fn render_json(
+    record: &Record,
+    column: Option<u32>,
+    origin: &Origin,
+    time: Option<&TimestampValue>,
+    out: &mut String,
+) {
+    out.push('{');
+    match time {
+        Some(TimestampValue::Wall(time)) => {
+            out.push_str("\"time\":");
+            write_json_str(out, time);
+            out.push(',');
+        }
+        Some(TimestampValue::Uptime(secs)) => {
+            let _ = write!(out, "\"time\":{:.6},", secs);
+        }
+        None => {}
+    }
+
+    out.push_str("\"level\":");
+    write_json_str(out, record.level().as_str());
+    out.push_str(",\"target\":");
+    write_json_str(out, record.target());
+    out.push_str(",\"module\":");
+    write_json_opt_str(out, record.module_path());
+    out.push_str(",\"file\":");
+    write_json_opt_str(out, record.file());
+    out.push_str(",\"line\":");
+    write_json_opt_u32(out, record.line());
+    out.push_str(",\"column\":");
+    write_json_opt_u32(out, column);
+
+    let _ = write!(out, ",\"thread\":{},\"thread_name\":", origin.id);
+    write_json_opt_str(out, origin.thread.name());
+
+    out.push_str(",\"msg\":");
+    match record.args().as_str() {
+        Some(msg) => write_json_str(out, msg),
+        None => write_json_str(out, &record.args().to_string()),
+    }
+
+    if record.key_values().count() > 0 {
+        out.push_str(",\"fields\":{");
+        let _ = record
+            .key_values()
+            .visit(&mut JsonFields { out, first: true });
+        out.push('}');
+    }
+    out.push('}');
+}
+//}}}
+//{{{ struct: JsonFields
+/// Visitor appending the key-values of a record to a JSON object.
+struct JsonFields<'a> {
+    out: &'a mut String,
+    first: bool,
+}
+//}}}
+//{{{ impl VisitSource for JsonFields
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        if !self.first {
+            self.out.push(',');
+        }
+        self.first = false;
+
+        write_json_str(self.out, key.as_str());
+        self.out.push(':');
+        write_json_value(self.out, &value);
+        Ok(())
+    }
+}
+//}}}
+//{{{ fun: write_json_value
+/// Appends a key-value value to `out`, keeping numbers and booleans unquoted.
+fn write_json_value(out: &mut String, value: &Value) {
+    if let Some(value) = value.to_bool() {
+        let _ = write!(out, "{}", value);
+    } else if let Some(value) = value.to_u64() {
+        let _ = write!(out, "{}", value);
+    } else if let Some(value) = value.to_i64() {
+        let _ = write!(out, "{}", value);
+    } else if let Some(value) = value.to_f64().filter(|value| value.is_finite()) {
+        let _ = write!(out, "{:?}", value);
+    } else if let Some(value) = value.to_borrowed_str() {
+        write_json_str(out, value);
+    } else {
+        write_json_str(out, &value.to_string());
+    }
+}
+//}}}
+//{{{ fun: write_json_opt_u32
+/// Appends an optional number to `out` as a JSON number or `null`.
+fn write_json_opt_u32(out: &mut String, value: Option<u32>) {
+    match value {
+        Some(value) => {
+            let _ = write!(out, "{}", value);
+        }
+        None => out.push_str("null"),
+    }
+}
+//}}}
+//{{{ fun: write_json_opt_str
+/// Appends an optional string to `out` as a JSON string or `null`.
+fn write_json_opt_str(out: &mut String, value: Option<&str>) {
+    match value {
+        Some(value) => write_json_str(out, value),
+        None => out.push_str("null"),
+    }
+}
+//}}}
+//{{{ fun: write_json_str
+/// Appends `value` to `out` as a quoted and escaped JSON string.
+fn write_json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+//}}}
+//}}}
+//{{{ collection: Origin
+//{{{ struct: Origin
+/// The thread a record was logged from, and how deep in spans it was, which the thread
+/// placeholders and the indentation of the message show.
+#[derive(Debug, Clone)]
+pub(crate) struct Origin {
+    thread: Thread,
+    id: u64,
+    depth: usize,
+}
+//}}}
+//{{{ impl Origin
+impl Origin {
+    /// The current thread at its current span depth.
+    pub(crate) fn current() -> Self {
+        Self {
+            thread: thread::current(),
+            id: thread_id(),
+            depth: span::depth(),
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ fun: thread_id
+/// A small number identifying the current thread, assigned the first time it is asked for.
+///
+/// Numbers start at 1 and never change for the lifetime of a thread, so they can be used to follow
+/// one thread through the log. 0 is returned while the thread is being torn down.
+pub(crate) fn thread_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static THREAD_ID: u64 = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    THREAD_ID.try_with(|id| *id).unwrap_or(0)
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_timestamp_formats() {
+        let start = Instant::now();
+
+        assert_eq!(Timestamp::None.now(start), None);
+
+        let utc = Timestamp::Utc.now(start).unwrap().to_string();
+        assert_eq!(utc.len(), "2024-10-10T14:03:21.123456Z".len());
+        assert!(utc.ends_with('Z'));
+
+        let local = Timestamp::Local.now(start).unwrap().to_string();
+        assert!(chrono::DateTime::parse_from_rfc3339(&local).is_ok());
+
+        let uptime = Timestamp::Uptime.now(start).unwrap().to_string();
+        assert!(uptime.starts_with("0."));
+        assert_eq!(uptime.split('.').nth(1).unwrap().len(), 6);
+    }
+
+    fn render(template: &str, record: &Record) -> String {
+        let mut out = String::new();
+        Template::parse(template).unwrap().render(
+            record,
+            None,
+            &Origin::current(),
+            None,
+            false,
+            &mut out,
+        );
+        out
+    }
+
+    #[test]
+    fn test_template_render() {
+        let record = Record::builder()
+            .args(format_args!("converged"))
+            .level(Level::Info)
+            .target("solver")
+            .module_path(Some("topohedral_linalg::cg"))
+            .file(Some("src/cg.rs"))
+            .line(Some(42))
+            .build();
+
+        assert_eq!(
+            render(
+                "{level:5}|{target:>8}|{module}|{file}:{line}|{msg}",
+                &record
+            ),
+            "INFO |  solver|topohedral_linalg::cg|src/cg.rs:42|converged"
+        );
+        assert_eq!(render("{{{msg}}} {time}!", &record), "{converged} !");
+        assert_eq!(
+            render("{thread_name}", &record),
+            thread::current().name().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_thread_ids() {
+        let record = Record::builder().args(format_args!("converged")).build();
+        let id = thread_id();
+        assert_eq!(thread_id(), id);
+        assert_eq!(render("{thread_id}", &record), id.to_string());
+
+        let unnamed = thread::spawn(move || {
+            let record = Record::builder().args(format_args!("converged")).build();
+            (thread_id(), render("{thread}|{thread_name}", &record))
+        })
+        .join()
+        .unwrap();
+        assert_ne!(unnamed.0, id);
+        assert_eq!(unnamed.1, format!("{}|<unnamed>", unnamed.0));
+
+        let named = thread::Builder::new()
+            .name("worker-3".into())
+            .spawn(move || {
+                let record = Record::builder().args(format_args!("converged")).build();
+                render("{thread}", &record)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(named, "worker-3");
+    }
+
+    #[test]
+    fn test_template_location() {
+        let record = Record::builder()
+            .args(format_args!("converged"))
+            .file(Some("src/cg.rs"))
+            .line(Some(42))
+            .build();
+
+        let mut out = String::new();
+        Template::parse("{location} {line}:{column}")
+            .unwrap()
+            .render(&record, Some(9), &Origin::current(), None, false, &mut out);
+        assert_eq!(out, "src/cg.rs:42:9 42:9");
+        assert_eq!(render("{location}", &record), "src/cg.rs:42");
+        assert_eq!(
+            render("{location}", &Record::builder().build()),
+            "<unknown>"
+        );
+    }
+
+    #[test]
+    fn test_template_fields() {
+        let fields: &[(&str, Value)] = &[
+            ("iter", Value::from(12u32)),
+            ("residual", Value::from(1.5e-9)),
+            ("method", Value::from("cg")),
+            ("note", Value::from("two words")),
+        ];
+        let record = Record::builder()
+            .args(format_args!("converged"))
+            .key_values(&fields)
+            .build();
+
+        assert_eq!(
+            render("{msg}|", &record),
+            "converged iter=12 residual=1.5e-9 method=cg note=\"two words\"|"
+        );
+    }
+
+    #[test]
+    fn test_template_errors() {
+        let err = Template::parse("{level} {mesage}").unwrap_err();
+        assert_eq!(err.column(), 9);
+        assert_eq!(
+            err.kind(),
+            &TemplateErrorKind::UnknownPlaceholder("mesage".into())
+        );
+
+        let err = Template::parse("[{level").unwrap_err();
+        assert_eq!(err.kind(), &TemplateErrorKind::Unclosed);
+        assert_eq!(err.column(), 2);
+
+        let err = Template::parse("{msg} }").unwrap_err();
+        assert_eq!(err.kind(), &TemplateErrorKind::Unmatched);
+
+        let err = Template::parse("{level:five}").unwrap_err();
+        assert_eq!(err.kind(), &TemplateErrorKind::InvalidSpec("five".into()));
+    }
+
+    #[test]
+    fn test_json_render() {
+        let fields: &[(&str, Value)] = &[
+            ("iter", Value::from(12u32)),
+            ("residual", Value::from(1.5e-9)),
+            ("converged", Value::from(true)),
+            ("method", Value::from("cg")),
+        ];
+        let record = Record::builder()
+            .args(format_args!("say \"hi\"\n"))
+            .level(Level::Warn)
+            .target("solver")
+            .module_path(Some("topohedral_linalg::cg"))
+            .line(Some(7))
+            .key_values(&fields)
+            .build();
+
+        let mut out = String::new();
+        let uptime = TimestampValue::Uptime(1.5);
+        Format::Json.render(
+            &record,
+            Some(3),
+            &Origin::current(),
+            Some(&uptime),
+            false,
+            &mut out,
+        );
+
+        assert_eq!(
+            out,
+            format!(
+                "{{\"time\":1.500000,\"level\":\"WARN\",\"target\":\"solver\",\
+                 \"module\":\"topohedral_linalg::cg\",\"file\":null,\"line\":7,\
+                 \"column\":3,\"thread\":{},\"thread_name\":\"{}\",\
+                 \"msg\":\"say \\\"hi\\\"\\n\",\
+                 \"fields\":{{\"iter\":12,\"residual\":1.5e-9,\"converged\":true,\
+                 \"method\":\"cg\"}}}}",
+                thread_id(),
+                thread::current().name().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_parse() {
+        assert_eq!(Format::parse("json"), Ok(Format::Json));
+        assert!(matches!(Format::parse("{msg}"), Ok(Format::Text(_))));
+    }
+
+    #[test]
+    fn test_template_color() {
+        let record = Record::builder()
+            .args(format_args!("converged"))
+            .level(Level::Warn)
+            .build();
+
+        let mut out = String::new();
+        Template::parse("{level:5}|{msg}").unwrap().render(
+            &record,
+            None,
+            &Origin::current(),
+            None,
+            true,
+            &mut out,
+        );
+        assert_eq!(out, "\x1b[33mWARN \x1b[0m|converged");
+    }
+
+    #[test]
+    fn test_style_colors() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+
+        assert!(Style::Auto.colors_with(true, true, env(&[])));
+        assert!(!Style::Auto.colors_with(true, false, env(&[])));
+        assert!(!Style::Auto.colors_with(false, false, env(&[("CLICOLOR_FORCE", "1")])));
+        assert!(!Style::Auto.colors_with(true, true, env(&[("NO_COLOR", "1")])));
+        assert!(Style::Auto.colors_with(true, true, env(&[("NO_COLOR", "")])));
+        assert!(Style::Auto.colors_with(true, false, env(&[("CLICOLOR_FORCE", "1")])));
+        assert!(!Style::Auto.colors_with(true, false, env(&[("CLICOLOR_FORCE", "0")])));
+        assert!(!Style::Auto.colors_with(true, true, env(&[("CLICOLOR", "0")])));
+        assert!(!Style::Auto.colors_with(
+            true,
+            false,
+            env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")])
+        ));
+        assert!(Style::Always.colors_with(true, false, env(&[("NO_COLOR", "1")])));
+        assert!(!Style::Always.colors_with(false, false, env(&[])));
+        assert!(!Style::Always.colors_with(false, true, env(&[("CLICOLOR_FORCE", "1")])));
+        assert!(!Style::Never.colors_with(true, true, env(&[])));
+        assert_eq!(Style::parse("never"), Ok(Style::Never));
+        assert!(Style::parse("off").is_err());
+    }
+
+    #[test]
+    fn test_timestamp_parse() {
+        assert_eq!(Timestamp::parse("uptime"), Ok(Timestamp::Uptime));
+        assert!(Timestamp::parse("UTC").is_err());
+    }
+}
+//}}}
@@ -32,6 +32,10 @@
 //!
 //! and compiling with the `enable_trace` feature.
 //!
+//! The macros only need to be imported themselves, as in `use topohedral_tracing::{debug, info};`,
+//! and do not require a dependency on the `log` crate. `Level` and `LevelFilter` are re-exported for
+//! use with `span!` and `TracingBuilder`.
+//!
 //! ## Runtime configuration
 //!
 //! Even with logging enabled at compile time, runtime logging filter will be dafault print
@@ -41,6 +45,11 @@
 //! ```shell
 //! export TOPO_LOG=<target>=<level>,<target>=<level>,...
 //! ```
+//! A target covers its own module and every module below it, so `topohedral_linalg=debug` also
+//! applies to `topohedral_linalg::dense::matrix`. When several targets match, the longest one wins,
+//! even if it is less verbose than a shorter one. The levels are `trace`, `debug`, `info`, `warn`,
+//! `error` and `off`, or equivalently `5` down to `0`.
+//!
 //! Additionally, there is a special target `all` which can be used to enable all logging of a
 //! given level. So, for example, to log everything at level `debug` we can do:
 //!
@@ -48,302 +57,904 @@
 //! export TOPO_LOG=all=debug
 //! ```
 //!
+//! The `all` level is only a default, so a noisy module can be quietened while everything else is
+//! traced:
+//!
+//! ```shell
+//! export TOPO_LOG=all=debug,topohedral_mesh=warn,topohedral_mesh::kernel=off
+//! ```
+//!
+//! A directive can keep only a fraction of the records at its level, given after an `@` as a decimal
+//! or a ratio, to trace hot kernels without keeping every record:
+//!
+//! ```shell
+//! export TOPO_LOG=all=info,topohedral_linalg=trace@0.01   # or trace@1/100
+//! ```
+//!
+//! Here one in every hundred `trace` records of `topohedral_linalg` is written, while its `debug` and
+//! less verbose records all are. The records kept are spread evenly and chosen by counting them, so
+//! a run logging the same records in the same order keeps the same ones. `TOPO_LOG_SEED`, or
+//! `TracingBuilder::seed`, shifts which ones are kept.
+//!
+//! Malformed directives, such as an unknown level, are skipped by `init()` and reported in a single
+//! line on stderr. `init_strict()` instead fails with the offending directive and its column.
+//!
+//! ## Structured key-values
+//!
+//! Numeric quantities can be attached to a record as typed key-values, listed before the message and
+//! separated from it by a semicolon:
+//!
+//! ```rust,no_run
+//! use topohedral_tracing::info;
+//!
+//! let (iter, residual) = (12, 1.5e-9);
+//! info!(target: "topohedral_linalg::cg", iter = iter, residual = residual; "converged");
+//! ```
+//!
+//! They are shown as `key=value` after the message in text output, `converged iter=12
+//! residual=1.5e-9`, and as typed entries of the `fields` object in JSON output. The values can be of
+//! any type implementing `log::kv::ToValue`, which covers numbers, booleans, characters and strings.
+//!
+//! ## Rate limiting
+//!
+//! A record logged inside a loop can be limited per call site by putting the limit before everything
+//! else in the macro, separated by a semicolon:
+//!
+//! ```rust,no_run
+//! use topohedral_tracing::warn;
+//!
+//! for iter in 0..1_000_000 {
+//!     warn!(every = 1000; "pivot below tolerance");
+//!     warn!(once; target: "topohedral_linalg", iter = iter; "falling back to dense storage");
+//!     warn!(per_second = 10; "step size reduced");
+//! }
+//! ```
+//!
+//! `every = N` logs the first record and every `N`th after it, `once` only the first, and
+//! `per_second = N` up to `N` records a second from a token bucket, letting a burst of `N` through at
+//! once. Records filtered out do not count towards the limit. A lone key-value named `every` or
+//! `per_second` is taken as a limit, so give it another name.
+//!
+//! Repeats can also be suppressed by the sink, whatever call site they come from. With
+//! `TOPO_LOG_DEDUP=on`, or `TracingBuilder::dedup(true)`, a record formatting the same as the one
+//! before it, apart from its timestamp, is only counted. When a different record arrives, or the
+//! logger is flushed, the count is written as a copy of the record, key-values included, ending in
+//! `(repeated N times)`. The first record logged after a flush is always written.
+//!
+//! ## Spans
+//!
+//! Besides single events, `span!` marks a region of code such as one call of a recursive algorithm.
+//! It takes a level, a name and any `key = value` pairs, and returns a guard which logs the entry
+//! when created and the exit, with the elapsed time, when dropped:
+//!
+//! ```rust,no_run
+//! use topohedral_tracing::{span, Level};
+//!
+//! fn refine(level: u32) {
+//!     let _span = span!(Level::Debug, "refine", level = level);
+//!     if level < 2 {
+//!         refine(level + 1);
+//!     }
+//! }
+//! ```
+//!
+//! Records logged on the same thread while a span is entered are indented in text output, which shows
+//! the call structure:
+//!
+//! ```text
+//! [DEBUG - 1   - solver:12] enter refine level=0
+//! [DEBUG - 1   - solver:12]   enter refine level=1
+//! [DEBUG - 1   - solver:12]   exit refine level=1 elapsed=1.204ms
+//! [DEBUG - 1   - solver:12] exit refine level=0 elapsed=2.873ms
+//! ```
+//!
+//! Like the other macros, `span!` is compiled out without the `enable_trace` feature.
+//!
+//! A whole function can be wrapped in a span with the `instrument` attribute, which logs the
+//! arguments with `Debug` on entry:
+//!
+//! ```rust,no_run
+//! use topohedral_tracing::instrument;
+//!
+//! #[instrument(level = "debug", skip(cells), ret, err)]
+//! fn split(cells: &[u32], depth: u32) -> Result<usize, String> {
+//!     Ok(cells.len() * 2)
+//! }
+//! ```
+//!
+//! `skip(..)` leaves out large arguments or those without `Debug`, `skip_all` leaves out every
+//! argument, `ret` also logs the return value and `err` logs a returned error at the error level.
+//! Without `enable_trace` the function is compiled unchanged.
+//!
+//! ## Timestamps
+//!
+//! By default records carry no time. Setting `TOPO_LOG_TIME` adds one to the start of the prefix:
+//!
+//! - `utc`: wall-clock time in UTC as RFC 3339, e.g. `2024-10-10T14:03:21.123456Z`.
+//! - `local`: wall-clock time in the local time zone as RFC 3339.
+//! - `uptime`: monotonic seconds since `init()`, e.g. `12.345678`.
+//! - `none`: no timestamp.
+//!
+//! The same choice is available from code through `TracingBuilder::timestamp`.
+//!
+//! ## Output format
+//!
+//! The layout of each record can be changed with a template in `TOPO_LOG_FORMAT`, or from code
+//! through `TracingBuilder::format`:
+//!
+//! ```shell
+//! export TOPO_LOG_FORMAT="{time} {level:5} [{thread}] {target} {file}:{line} {msg}"
+//! ```
+//!
+//! The placeholders are `time`, `level`, `thread`, `thread_id`, `thread_name`, `module`, `file`,
+//! `line`, `column`, `location`, `target` and `msg`. `thread` is the name of the thread if it has
+//! one, and otherwise a small number assigned to each thread the first time it logs, which is what
+//! `thread_id` always shows. `location` is the `path:line:column` the record was logged from,
+//! which most terminals and editors turn into a link. A width can be given after a colon, as in
+//! `{level:5}`, optionally prefixed by `<` or `>` to align left or right, and `{{` and `}}` stand
+//! for literal braces. The default layout is
+//! `[{level:5} - {thread:3} - {module}:{line}] {msg}`.
+//!
+//! Setting `TOPO_LOG_FORMAT=json`, or calling `TracingBuilder::json`, writes one JSON object per line
+//! instead, for ingestion by analysis scripts:
+//!
+//! ```json
+//! {"time":"2024-10-10T14:03:21.123456Z","level":"INFO","target":"topohedral_linalg::cg","module":"topohedral_linalg::cg","file":"src/cg.rs","line":42,"column":9,"thread":2,"thread_name":"solver-2","msg":"converged","fields":{"iter":12}}
+//! ```
+//!
+//! The `fields` object holds any structured key-values of the record and is omitted when there are
+//! none, and `thread_name` is `null` for threads without a name. The time is in UTC unless
+//! `TOPO_LOG_TIME` says otherwise.
+//!
+//! ## Colors
+//!
+//! The level is colored only when writing to stderr or stdout attached to a terminal, so redirected
+//! output and log files stay free of escape codes. The `NO_COLOR` and `CLICOLOR_FORCE` conventions
+//! are honoured, and `TOPO_LOG_STYLE` overrides the detection:
+//!
+//! - `auto`: color a terminal unless `NO_COLOR` is set, or any console when `CLICOLOR_FORCE` is set.
+//! - `always`: always color stderr and stdout, even when redirected.
+//! - `never`: never color.
+//!
+//! The style is chosen per sink, with `TOPO_LOG_<NAME>_STYLE` or `SinkBuilder::style`. Files, pipes
+//! and JSON records are never colored, whatever the style.
+//!
+//! ## Writing to a file
+//!
+//! Long runs on a cluster are better logged to a file than to stderr. Setting `TOPO_LOG_FILE`
+//! appends the records to the given file instead, and the file can be rotated so that it does not
+//! fill the disk:
+//!
+//! ```shell
+//! export TOPO_LOG_FILE=solver.log
+//! export TOPO_LOG_ROTATE=100MB    # or hourly, daily, never
+//! export TOPO_LOG_KEEP=3          # rotated files to keep, 5 by default
+//! export TOPO_LOG_COMPRESS=gzip   # or none
+//! ```
+//!
+//! When the file is rotated it is renamed to `solver.log.1`, or gzipped to `solver.log.1.gz`, the
+//! older files are shifted up and those beyond the retention count are deleted. Sizes take a `K`, `M`
+//! or `G` suffix in binary multiples. Time-based rotation happens at the start of each hour or day in
+//! local time. From code the same is configured with `Target::File(FileTarget::new(path))`.
+//! Records written to a file are not colored, and each is written out as soon as it is logged, so
+//! none are lost if the process exits or crashes without calling `flush()`.
+//!
+//! ## Writing on a background thread
+//!
+//! Writing to a slow terminal or a busy disk holds up the thread logging the record. With
+//! `TOPO_LOG_QUEUE` set, records are still filtered and formatted by the thread logging them, but are
+//! then queued for a background thread which writes them:
+//!
+//! ```shell
+//! export TOPO_LOG_QUEUE=4096            # records held in the queue
+//! export TOPO_LOG_OVERFLOW=drop_oldest  # or block, drop_newest
+//! ```
+//!
+//! When the queue is full, `block` waits until the background thread makes room, which is the default,
+//! `drop_newest` discards the record being logged and `drop_oldest` discards the oldest queued record.
+//! Dropped records are replaced in the output by a warning saying how many were lost. From code the
+//! same is set with `TracingBuilder::queue` and `TracingBuilder::overflow`.
+//!
+//! Queued records are lost if the process exits before they are written, so call `flush()` before
+//! returning from `main`, or keep a guard which does so when dropped:
+//!
+//! ```rust,no_run
+//! use topohedral_tracing::{FlushGuard, TracingBuilder};
+//!
+//! TracingBuilder::from_env().queue(4096).init().unwrap();
+//! let _guard = FlushGuard::new();
+//! ```
+//!
+//! ## Multiple sinks
+//!
+//! Records can be sent to several sinks at once, each with its own filter, writer, format and
+//! colors. For example, to show warnings on the console while tracing `topohedral_geom` to a file:
+//!
+//! ```shell
+//! export TOPO_LOG=all=warn
+//! export TOPO_LOG_SINKS=geom
+//! export TOPO_LOG_GEOM=topohedral_geom=trace
+//! export TOPO_LOG_GEOM_FILE=geom.log
+//! ```
+//!
+//! The level of every target is set through `all`, as a bare `warn` would name a target instead:
+//!
+//! ```
+//! use topohedral_tracing::{topo_enabled, Level, TracingBuilder};
+//!
+//! TracingBuilder::new().parse_directives("all=warn").init().unwrap();
+//! assert!(topo_enabled("topohedral_mesh", Level::Warn));
+//! assert!(!topo_enabled("topohedral_mesh", Level::Info));
+//! ```
+//!
+//! `TOPO_LOG_SINKS` lists the names of the sinks beside the main one. The filter of each is read
+//! from `TOPO_LOG_<NAME>` and its other settings from the same variables as the main sink with the
+//! name inserted, such as `TOPO_LOG_GEOM_FORMAT` or `TOPO_LOG_GEOM_ROTATE`. A sink writes to stderr
+//! unless it is given a file. From code, sinks are added with `TracingBuilder::sink`, and
+//! `set_sink_filter("geom", "...")` replaces the filter of one of them at runtime.
+//!
+//! ## Programmatic configuration
+//!
+//! The logger can also be configured from code with `TracingBuilder`, in which case `TOPO_LOG` is
+//! only one of the inputs:
+//!
+//! ```rust,no_run
+//! use topohedral_tracing::{LevelFilter, Target, TracingBuilder};
+//!
+//! TracingBuilder::new()
+//!     .default_level(LevelFilter::Warn)
+//!     .filter("topohedral_mesh", LevelFilter::Debug)
+//!     .env_var("TOPO_LOG")
+//!     .writer(Target::Stdout)
+//!     .init()
+//!     .unwrap();
+//! ```
+//!
+//! Settings are applied in the order they are given, so here `TOPO_LOG` can override the defaults
+//! set before it.
+//!
+//! ## Reconfiguring at runtime
+//!
+//! The filter of the installed logger can be replaced while the program runs with
+//! `set_filter("topohedral_mesh=trace")`, while `reload()` reads `TOPO_LOG` and any filter files
+//! again. With `TracingBuilder::watch` a background thread does this whenever they change, so the
+//! verbosity of a long-running solver can be raised by editing a file:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use topohedral_tracing::TracingBuilder;
+//!
+//! TracingBuilder::from_env()
+//!     .config_file("solver.filter")
+//!     .watch(Duration::from_secs(1))
+//!     .init()
+//!     .unwrap();
+//! ```
+//!
+//! A filter can also be overridden on one thread only, for the duration of a closure, which lets
+//! parallel tests or solver instances run with their own verbosity:
+//!
+//! ```rust
+//! use topohedral_tracing::with_filter;
+//!
+//! let cells = with_filter("topohedral_mesh=trace", || 42).unwrap();
+//! ```
+//!
+//! The directives of `with_filter` are layered on top of the filter of every sink: targets they cover
+//! are logged at the level they give, more or less verbose, and other targets are filtered as before.
+//! Records logged by other threads at the same time are not affected.
+//!
+//! ## Asserting on records in tests
+//!
+//! Tests can check that a warning was emitted by capturing the records in memory. `testing::capture()`
+//! returns a guard which keeps every record logged on the current thread while it is alive, whatever
+//! the filter and whether or not a logger is installed, and `testing::capture_global()` does the same
+//! for every thread:
+//!
+//! ```rust
+//! # if cfg!(feature = "enable_trace") {
+//! use topohedral_tracing::{assert_logged, testing, warn, Level};
+//!
+//! let logs = testing::capture();
+//! warn!(det = 0.0; "matrix is singular");
+//! assert_logged!(Level::Warn, contains "singular");
+//! assert_eq!(logs.records()[0].field("det"), Some("0.0"));
+//! # }
+//! ```
+//!
+//! Each captured record holds the level, target, message and key-values. `assert_logged!` looks through
+//! the captures alive on the current thread and lists what was captured when nothing matches. The
+//! records are only logged if the crate under test enables its `enable_trace` feature for its tests.
 //!
 //--------------------------------------------------------------------------------------------------
 
 //{{{ crate imports
+mod background;
+mod builder;
+mod callsite;
+mod file;
+mod filter;
+mod format;
+mod max_level;
+mod reload;
+mod scope;
+mod sink;
+mod span;
+#[cfg(test)]
+mod test_util;
+pub mod testing;
+mod writer;
+pub use background::Overflow;
+pub use builder::{SinkBuilder, TracingBuilder};
+pub use callsite::Callsite;
+pub use file::{FileTarget, Rotation};
+pub use filter::{FilterParseError, FilterParseErrorKind};
+pub use format::{Style, TemplateError, TemplateErrorKind, Timestamp};
+pub use reload::{reload, set_filter, set_sink_filter, ReloadError};
+pub use scope::with_filter;
+use sink::Sink;
+pub use span::Span;
+pub use topohedral_tracing_macros::instrument;
+pub use writer::Target;
 //}}}
 //{{{ std imports
-use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fmt;
-use std::sync::Mutex;
-use std::thread;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
 //}}}
 //{{{ dep imports
-use colored::Colorize;
-use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use log::kv::Value;
+pub use log::{Level, LevelFilter};
+use log::{Metadata, Record, SetLoggerError};
 //}}}
 //--------------------------------------------------------------------------------------------------
-//{{{ impl fmt::Display for ThreadId
-struct ThreadIdWrapper(thread::ThreadId);
-impl fmt::Display for ThreadIdWrapper {
+//{{{ collection: InitError
+//{{{ enum: InitError
+/// Error returned when the tracing system cannot be initialized.
+#[derive(Debug)]
+pub enum InitError {
+    /// A global `log` logger has already been installed.
+    SetLogger(SetLoggerError),
+    /// A filter directive is malformed, only returned in strict mode.
+    Filter(FilterParseError),
+    /// A filter file could not be read, or the log file could not be opened.
+    Io(io::Error),
+    /// A format template is malformed, only returned in strict mode.
+    Template(TemplateError),
+    /// An environment variable holding a setting other than the filter has an invalid value,
+    /// only returned in strict mode.
+    InvalidEnv {
+        /// The name of the variable.
+        var: String,
+        /// The value of the variable.
+        value: String,
+        /// What is wrong with the value.
+        reason: String,
+    },
+}
+//}}}
+//{{{ impl fmt::Display for InitError
+impl fmt::Display for InitError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Use the Debug implementation to extract the number
-        let thread_id_str = format!("{:?}", self.0);
-
-        // Extract the number part from "ThreadId(num)"
-        let num_str = if let Some(start) = thread_id_str.find('(') {
-            if let Some(end) = thread_id_str.find(')') {
-                &thread_id_str[start + 1..end]
-            } else {
-                "Unknown"
+        match self {
+            InitError::SetLogger(err) => err.fmt(f),
+            InitError::Filter(err) => err.fmt(f),
+            InitError::Io(err) => err.fmt(f),
+            InitError::Template(err) => err.fmt(f),
+            InitError::InvalidEnv { var, value, reason } => {
+                write!(f, "invalid value `{}` for {}: {}", value, var, reason)
             }
-        } else {
-            "Unknown"
-        };
+        }
+    }
+}
+//}}}
+//{{{ impl std::error::Error for InitError
+impl std::error::Error for InitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitError::SetLogger(err) => Some(err),
+            InitError::Filter(err) => Some(err),
+            InitError::Io(err) => Some(err),
+            InitError::Template(err) => Some(err),
+            InitError::InvalidEnv { .. } => None,
+        }
+    }
+}
+//}}}
+//{{{ impl From<SetLoggerError> for InitError
+impl From<SetLoggerError> for InitError {
+    fn from(err: SetLoggerError) -> Self {
+        InitError::SetLogger(err)
+    }
+}
+//}}}
+//{{{ impl From<FilterParseError> for InitError
+impl From<FilterParseError> for InitError {
+    fn from(err: FilterParseError) -> Self {
+        InitError::Filter(err)
+    }
+}
+//}}}
+//{{{ impl From<TemplateError> for InitError
+impl From<TemplateError> for InitError {
+    fn from(err: TemplateError) -> Self {
+        InitError::Template(err)
+    }
+}
+//}}}
+//{{{ impl From<io::Error> for InitError
+impl From<io::Error> for InitError {
+    fn from(err: io::Error) -> Self {
+        InitError::Io(err)
+    }
+}
+//}}}
+//}}}
+//{{{ mod: __private
+/// Items used by the expansions of the macros, which are not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    pub use log::kv::ToValue;
+    pub use log::Level;
 
-        // Now format it respecting width and alignment
-        f.write_str(&format!(
-            "{:width$}",
-            num_str,
-            width = f.width().unwrap_or(0)
-        ))
+    /// Whether the logging macros log a record, which is the case if [`topo_enabled`] says so
+    /// or a capture receives the records of the current thread.
+    ///
+    /// The global max level is loaded once, so a disabled record still costs a single load.
+    ///
+    /// [`topo_enabled`]: crate::topo_enabled
+    #[inline]
+    pub fn enabled(target: &str, level: Level) -> bool {
+        let max_level = log::max_level();
+        level <= max_level && (crate::logger_enabled(target, level) || crate::testing::capturing())
     }
 }
 //}}}
 //{{{ collection: constants
-static LOGGER: Mutex<Option<Box<dyn log::Log>>> = Mutex::new(None);
+/// The logger installed by [`TracingBuilder::init`], kept so that it can be reconfigured.
+static LOGGER: OnceLock<TopoHedralLogger> = OnceLock::new();
 //}}}
 //{{{ collection TopoHedralLogger
 //{{{ struct TopoHedralLogger
+/// The logger installed as the global `log` logger.
+///
+/// Records are fanned out to one or more sinks, each with its own filter. Deciding whether a
+/// record is enabled never takes a lock: the most verbose level of any sink is cached in an atomic
+/// for a quick rejection, and the filters themselves are immutable snapshots which are swapped out
+/// as a whole on reload. Only the writers synchronise.
 struct TopoHedralLogger {
-    all: LevelFilter,
-    filters: HashMap<String, LevelFilter>,
+    /// The main sink first, followed by any added with [`TracingBuilder::sink`].
+    sinks: Vec<Sink>,
+    /// The most verbose `max_level` of the sink filters, as a `usize`.
+    max_level: AtomicUsize,
+    /// The values last read from the environment and from files by the sources of each sink.
+    inputs: Mutex<Vec<Vec<Option<OsString>>>>,
+    /// When the logger was built, the origin of [`Timestamp::Uptime`].
+    start: Instant,
 }
 //}}}
 //{{{ impl TopoHedralLogger
 impl TopoHedralLogger {
-    fn new() -> Self {
-        let mut filters = HashMap::<String, LevelFilter>::new();
-        let mut all = LevelFilter::Off;
-
-        match std::env::var("TOPO_LOG") {
-            Ok(val) => {
-                let targets: Vec<&str> = val.split(",").collect();
-                for key in targets {
-                    let target: String;
-                    let level: LevelFilter;
-                    if key.contains("=") {
-                        let peices: Vec<&str> = key.split("=").collect();
-                        target = peices[0].to_string();
+    /// Creates the logger from its sinks, the inputs their filters were built from and the time
+    /// uptime is counted from.
+    fn new(sinks: Vec<Sink>, inputs: Vec<Vec<Option<OsString>>>, start: Instant) -> Self {
+        let logger = Self {
+            sinks,
+            max_level: AtomicUsize::new(0),
+            inputs: Mutex::new(inputs),
+            start,
+        };
+        logger
+            .max_level
+            .store(logger.filter_max_level() as usize, Ordering::Relaxed);
+        logger
+    }
 
-                        level = match peices[1] {
-                            "trace" | "5" => LevelFilter::Trace,
-                            "debug" | "4" => LevelFilter::Debug,
-                            "info" | "3" => LevelFilter::Info,
-                            "warn" | "2" => LevelFilter::Warn,
-                            "error" | "1" => LevelFilter::Error,
-                            _ => LevelFilter::Info,
-                        }
-                    } else {
-                        target = key.to_string();
-                        level = LevelFilter::Info;
-                    }
+    /// The most verbose level enabled by the filter of any sink.
+    fn filter_max_level(&self) -> LevelFilter {
+        self.sinks
+            .iter()
+            .map(|sink| sink.filter.load().max_level())
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
 
-                    if target == "all" {
-                        all = level;
-                    } else {
-                        filters.insert(target, level);
-                    }
-                }
+    /// Hands the record to every sink, together with the column it was logged from if known.
+    fn log_at(&self, record: &Record, column: Option<u32>) {
+        testing::deliver(record);
+        if scope::overridden() || record.level() as usize <= self.max_level.load(Ordering::Relaxed)
+        {
+            for sink in &self.sinks {
+                sink.log(record, column, self.start);
             }
-            Err(std::env::VarError::NotPresent) => {}
-            Err(std::env::VarError::NotUnicode(_)) => {}
         }
+    }
 
-        Self { filters, all }
+    /// Whether this logger is the global `log` logger.
+    fn is_installed(&self) -> bool {
+        let global = log::logger() as *const dyn log::Log as *const ();
+        std::ptr::eq(global, self as *const Self as *const ())
     }
 }
 //}}}
 //{{{ impl log::Log for TopoHedralLogger
 impl log::Log for TopoHedralLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        let target = metadata.target();
-        let mut target_level = match self.filters.get(target) {
-            Some(level) => *level,
-            None => self.all,
-        };
-        target_level = std::cmp::max(target_level, self.all);
-
-        metadata.level() <= target_level
+        if let Some(level) = scope::level(metadata.target()) {
+            return metadata.level() <= level;
+        }
+        metadata.level() as usize <= self.max_level.load(Ordering::Relaxed)
+            && self.sinks.iter().any(|sink| sink.enabled(metadata))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            eprintln!("{}", record.args());
-        }
+        self.log_at(record, None);
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        for sink in &self.sinks {
+            sink.flush(self.start);
+        }
+    }
 }
 //}}}
 //}}}
+//{{{ fun: installed_logger
+/// Returns the logger of this crate if it is the global `log` logger.
+fn installed_logger() -> Option<&'static TopoHedralLogger> {
+    LOGGER.get().filter(|logger| logger.is_installed())
+}
+//}}}
 //{{{ fun: init
 /// Initialize the tracing system.
 ///
 /// This must be called before any tracing can occur. Typically this is called from the main
 /// function of the program.
-pub fn init() -> Result<(), SetLoggerError> {
-    let mut logger_guard = LOGGER.lock().unwrap();
-    *logger_guard = Some(Box::new(TopoHedralLogger::new()));
-    log::set_max_level(LevelFilter::Trace);
-    // log::set_boxed_logger(logger_guard.take().unwrap())?;
-    Ok(())
+///
+/// The logger is installed as the global `log` logger, so records emitted through the `log` crate
+/// by dependencies are filtered and formatted in the same way as those from the macros in this
+/// crate. Returns an error if a global logger has already been installed.
+///
+/// Malformed `TOPO_LOG` directives are skipped and reported in a single line on stderr, the
+/// remaining directives still take effect. Use [`init_strict`] to treat them as an error instead.
+/// To configure the logger from code use [`TracingBuilder`].
+pub fn init() -> Result<(), InitError> {
+    TracingBuilder::from_env().init()
+}
+//}}}
+//{{{ fun: init_strict
+/// Initialize the tracing system, failing if any `TOPO_LOG` directive is malformed.
+///
+/// This behaves like [`init`] except that the first malformed directive is returned as
+/// [`InitError::Filter`] and no logger is installed.
+pub fn init_strict() -> Result<(), InitError> {
+    TracingBuilder::from_env().strict(true).init()
+}
+//}}}
+//{{{ fun: flush
+/// Writes out every record logged so far.
+///
+/// This waits for the background threads of sinks configured with [`TracingBuilder::queue`] to
+/// write the records queued for them, then flushes the writers and waits for any rotated file
+/// being compressed. It is the same as `log::logger().flush()`, and does nothing if the logger of
+/// this crate is not installed.
+pub fn flush() {
+    if let Some(logger) = installed_logger() {
+        log::Log::flush(logger);
+    }
+}
+//}}}
+//{{{ collection: FlushGuard
+//{{{ struct: FlushGuard
+/// Guard which calls [`flush`] when dropped, so that no record is lost when `main` returns.
+///
+/// ```rust,no_run
+/// use topohedral_tracing::{FlushGuard, TracingBuilder};
+///
+/// fn main() {
+///     TracingBuilder::from_env().queue(4096).init().unwrap();
+///     let _guard = FlushGuard::new();
+///     // ...
+/// }
+/// ```
+#[derive(Debug, Default)]
+#[must_use = "the records are flushed as soon as the guard is dropped"]
+pub struct FlushGuard {
+    _private: (),
+}
+//}}}
+//{{{ impl FlushGuard
+impl FlushGuard {
+    /// Creates a guard flushing the installed logger when dropped.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+//}}}
+//{{{ impl Drop for FlushGuard
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        flush();
+    }
+}
+//}}}
+//}}}
+//{{{ fun: topo_enabled
+/// Checks whether a record with the given target and level would be logged.
+///
+/// The logging macros call this before formatting anything, so a disabled record costs no more
+/// than this check. Records more verbose than any filter directive are rejected with a single
+/// atomic load of the global `log` max level, which this crate keeps in step with its filter at
+/// runtime independently of the `max_level_*` features of the `log` crate. Other records are
+/// checked against the full filter of the installed logger, which also takes no lock. A
+/// [`with_filter`] scope on the current thread takes precedence over the filter. While any scope
+/// or capture is alive the max level is raised to `trace`, so that the records they let through
+/// reach the full check.
+///
+/// Captures made with [`testing::capture`] do not change the result. The logging macros hand
+/// them every record regardless.
+#[inline]
+pub fn topo_enabled(target: &str, level: Level) -> bool {
+    level <= log::max_level() && logger_enabled(target, level)
+}
+//}}}
+//{{{ fun: logger_enabled
+/// Whether the global `log` logger enables a record, without first checking the max level.
+#[inline]
+fn logger_enabled(target: &str, level: Level) -> bool {
+    log::logger().enabled(&Metadata::builder().level(level).target(target).build())
 }
 //}}}
 //{{{ fun: topo_log
-/// Logs a message with the specified target, level, module, line, and arguments.
+/// Logs a message with the specified target, level, call site, and arguments.
 ///
 /// This function is used internally by the `trace!`, `debug!`, and `info!` macros to log
-/// messages with the appropriate metadata. It acquires a lock on the `LOGGER` global
-/// variable, and if a logger is present, it logs the message with the specified
-/// attributes.
-///
-/// The `log_color` variable is used to determine the color of the log message based on
-/// the log level. The message is then formatted and logged using the logger's `log`
-/// method.
+/// messages with the appropriate metadata. It builds a `log::Record` and hands it to the global
+/// `log` logger, which is the `TopoHedralLogger` once [`init`] has been called. Filtering and
+/// formatting of the record happen there.
 ///
 /// # Arguments
 /// - target: &str - The target of the log message.
 /// - level: Level - The level of the log message.
-/// - module: &str - The module of the log message.
-/// - line: u32 - The line of the log message.
+/// - callsite: &Callsite - The module, file, line and column of the log message.
 /// - args: Arguments - The arguments of the log message.
-pub fn topo_log(target: &str, level: Level, module: &str, line: u32, args: fmt::Arguments) {
-    let mut logger_guard = LOGGER.lock().unwrap();
-    if let Some(logger) = &mut *logger_guard {
-        let thread_id = thread::current().id();
-
-        let log_color = match level {
-            Level::Error => "red",
-            Level::Warn => "yellow",
-            Level::Info => "green",
-            Level::Debug => "blue",
-            Level::Trace => "magenta",
-        };
-
-        logger.log(
-            &log::Record::builder()
-                .args(format_args!(
-                    "[{:<5} - {:<3} - {}:{}] {}",
-                    level.as_str().color(log_color),
-                    ThreadIdWrapper(thread_id),
-                    module,
-                    line,
-                    args
-                ))
-                .file(Some(module))
-                .line(Some(line))
-                .level(level)
-                .target(target)
-                .build(),
-        );
+pub fn topo_log(target: &str, level: Level, callsite: &'static Callsite, args: fmt::Arguments) {
+    topo_log_kv(target, level, callsite, args, &[]);
+}
+//}}}
+//{{{ fun: topo_log_kv
+/// Logs a message with key-values attached, as [`topo_log`] does without them.
+///
+/// This is used by the logging macros when given `key = value` pairs before the message. The
+/// key-values are shown as `key=value` after the message in text output and as typed fields in
+/// JSON output.
+pub fn topo_log_kv(
+    target: &str,
+    level: Level,
+    callsite: &'static Callsite,
+    args: fmt::Arguments,
+    kvs: &[(&str, Value)],
+) {
+    let record = log::Record::builder()
+        .args(args)
+        .file_static(Some(callsite.file()))
+        .module_path_static(Some(callsite.module()))
+        .line(Some(callsite.line()))
+        .level(level)
+        .target(target)
+        .key_values(&kvs)
+        .build();
+    match installed_logger() {
+        Some(logger) => logger.log_at(&record, Some(callsite.column())),
+        None => {
+            testing::deliver(&record);
+            log::logger().log(&record);
+        }
     }
 }
 //}}}
-//{{{ macro: trace
-/// The `trace!` macro is used to log a trace message. Trace is the highest level of logging.
+//{{{ macro: __topo_log
+/// The expansion shared by the logging macros, taking the target, the level, the rate limit and
+/// the key-values in brackets, and the format arguments.
+///
+/// The rate limit, a method of [`Callsite`] with its argument, is only consulted once the record
+/// is known to be enabled, so that records filtered out do not count towards it.
+#[doc(hidden)]
 #[macro_export]
-macro_rules! trace {
-    (target: $target:expr, $($arg:tt)+) => {
+macro_rules! __topo_log {
+    ($target:expr, $level:expr, [$($limit:ident($($rate:expr)?))?], [$($key:ident = $value:expr),*], $($arg:tt)+) => {
         #[cfg(feature = "enable_trace")]
         {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log($target, log::Level::Trace, module, location.line(), format_args!($($arg)+));
+            static __TOPO_CALLSITE: $crate::Callsite = $crate::Callsite::new(
+                ::core::module_path!(),
+                ::core::file!(),
+                ::core::line!(),
+                ::core::column!(),
+            );
+            let target = $target;
+            if $crate::__private::enabled(target, $level) $(&& __TOPO_CALLSITE.$limit($($rate)?))? {
+                $crate::topo_log_kv(
+                    target,
+                    $level,
+                    &__TOPO_CALLSITE,
+                    ::core::format_args!($($arg)+),
+                    &[$((::core::stringify!($key), $crate::__private::ToValue::to_value(&$value))),*],
+                );
+            }
         }
     };
+}
+//}}}
+//{{{ macro: __topo_parse
+/// Splits the arguments of a logging macro, after any rate limit, into the target, the
+/// key-values and the format arguments.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __topo_parse {
+    ($level:expr, [$($limit:tt)*], target: $target:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::__topo_log!($target, $level, [$($limit)*], [$($key = $value),+], $($arg)+)
+    };
+    ($level:expr, [$($limit:tt)*], target: $target:expr, $($arg:tt)+) => {
+        $crate::__topo_log!($target, $level, [$($limit)*], [], $($arg)+)
+    };
+    ($level:expr, [$($limit:tt)*], $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::__topo_log!(::core::module_path!(), $level, [$($limit)*], [$($key = $value),+], $($arg)+)
+    };
+    ($level:expr, [$($limit:tt)*], $($arg:tt)+) => {
+        $crate::__topo_log!(::core::module_path!(), $level, [$($limit)*], [], $($arg)+)
+    };
+}
+//}}}
+//{{{ macro: trace
+/// The `trace!` macro is used to log a trace message. Trace is the highest level of logging.
+#[macro_export]
+macro_rules! trace {
+    (every = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Trace, [every($n)], $($arg)+)
+    };
+    (once ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Trace, [once()], $($arg)+)
+    };
+    (per_second = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Trace, [per_second($n)], $($arg)+)
+    };
     ($($arg:tt)+) => {
-
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log(module, log::Level::Trace, module, location.line(), format_args!($($arg)+));
-        }
-     };
+        $crate::__topo_parse!($crate::__private::Level::Trace, [], $($arg)+)
+    };
 }
 //}}}
 //{{{ macro: debug
 ///  The `debug!` macro is used to log a debug message. Debug is the second highest level of logging.
 #[macro_export]
-macro_rules! debug{
-    (target: $target:expr, $($arg:tt)+) => {
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log($target, log::Level::Debug, module, location.line(), format_args!($($arg)+));
-        }
+macro_rules! debug {
+    (every = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Debug, [every($n)], $($arg)+)
+    };
+    (once ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Debug, [once()], $($arg)+)
+    };
+    (per_second = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Debug, [per_second($n)], $($arg)+)
     };
     ($($arg:tt)+) => {
-
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log(module, log::Level::Debug, module, location.line(), format_args!($($arg)+));
-        }
-     };
+        $crate::__topo_parse!($crate::__private::Level::Debug, [], $($arg)+)
+    };
 }
 //}}}
 //{{{ macro: info
 /// The `info!` macro is used to log an info message. Info is the third highest level of logging.
 #[macro_export]
-macro_rules! info{
-    (target: $target:expr, $($arg:tt)+) => {
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log($target, log::Level::Info, module, location.line(), format_args!($($arg)+));
-        }
+macro_rules! info {
+    (every = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Info, [every($n)], $($arg)+)
+    };
+    (once ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Info, [once()], $($arg)+)
+    };
+    (per_second = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Info, [per_second($n)], $($arg)+)
     };
     ($($arg:tt)+) => {
-
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log(module, log::Level::Info, module, location.line(), format_args!($($arg)+));
-        }
-     };
+        $crate::__topo_parse!($crate::__private::Level::Info, [], $($arg)+)
+    };
 }
 //}}}
 //{{{ macro: warn
 /// The `warn!` macro is used to log a warning message. Warn is the fourth highest level of logging.
 #[macro_export]
-macro_rules! warn{
-    (target: $target:expr, $($arg:tt)+) => {
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log($target, log::Level::Warn, module, location.line(), format_args!($($arg)+));
-        }
+macro_rules! warn {
+    (every = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Warn, [every($n)], $($arg)+)
+    };
+    (once ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Warn, [once()], $($arg)+)
+    };
+    (per_second = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Warn, [per_second($n)], $($arg)+)
     };
     ($($arg:tt)+) => {
-
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log(module, log::Level::Warn, module, location.line(), format_args!($($arg)+));
-        }
-     };
+        $crate::__topo_parse!($crate::__private::Level::Warn, [], $($arg)+)
+    };
 }
 //}}}
 //{{{ macro: error
 /// The `error!` macro is used to log an error message. Error is the lowest level of logging.
 #[macro_export]
 macro_rules! error {
-    (target: $target:expr, $($arg:tt)+) => {
-        #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log($target, log::Level::Error, module, location.line(), format_args!($($arg)+));
-        }
+    (every = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Error, [every($n)], $($arg)+)
+    };
+    (once ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Error, [once()], $($arg)+)
+    };
+    (per_second = $n:expr ; $($arg:tt)+) => {
+        $crate::__topo_parse!($crate::__private::Level::Error, [per_second($n)], $($arg)+)
     };
     ($($arg:tt)+) => {
-
+        $crate::__topo_parse!($crate::__private::Level::Error, [], $($arg)+)
+    };
+}
+//}}}
+//{{{ macro: span
+/// The `span!` macro enters a span and returns a [`Span`] guard which exits it when dropped.
+///
+/// It takes the level, the name of the span and any number of `key = value` pairs, whose values
+/// are shown with their `Display` implementation. A record `enter name key=value` is logged at
+/// the given level on entry, and `exit name key=value elapsed=1.234ms` on exit, while the records
+/// logged on the same thread in between are indented:
+///
+/// ```
+/// use topohedral_tracing::{span, Level};
+///
+/// fn refine(level: u32) {
+///     let _span = span!(Level::Debug, "refine", level = level);
+///     if level < 3 {
+///         refine(level + 1);
+///     }
+/// }
+/// ```
+///
+/// Like the other macros it is compiled out without the `enable_trace` feature, in which case an
+/// inactive guard is returned. Only the level is still evaluated, so that its imports stay in use,
+/// while the target, name and values are not.
+#[macro_export]
+macro_rules! span {
+    (target: $target:expr, $level:expr, $name:expr $(, $key:ident = $value:expr)* $(,)?) => {{
         #[cfg(feature = "enable_trace")]
-        {
-            let location = std::panic::Location::caller();
-            let module = module_path!();
-            topo_log(module, log::Level::Error, module, location.line(), format_args!($($arg)+));
-        }
-     };
+        let span = {
+            static __TOPO_CALLSITE: $crate::Callsite = $crate::Callsite::new(
+                ::core::module_path!(),
+                ::core::file!(),
+                ::core::line!(),
+                ::core::column!(),
+            );
+            let target = $target;
+            let level = $level;
+            if $crate::__private::enabled(target, level) {
+                $crate::Span::enter(
+                    target,
+                    level,
+                    &__TOPO_CALLSITE,
+                    $name,
+                    ::core::format_args!(::core::concat!($(" ", ::core::stringify!($key), "={}"),*), $($value),*),
+                )
+            } else {
+                $crate::Span::none()
+            }
+        };
+        #[cfg(not(feature = "enable_trace"))]
+        let span = {
+            let _ = $level;
+            $crate::Span::none()
+        };
+        span
+    }};
+    ($level:expr, $name:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::span!(target: ::core::module_path!(), $level, $name $(, $key = $value)*)
+    };
 }
 //}}}
 //-------------------------------------------------------------------------------------------------
@@ -368,5 +979,24 @@ mod tests {
         error!("Hello, world! This is a test 1 {}", 5);
         error!(target: "test",  "Hello, world! This is a test 2 {}", 5);
     }
+
+    #[test]
+    fn test_key_values() {
+        let logs = testing::capture();
+        info!(target: "test", iter = 12, residual = 1.5e-9; "converged after {} iterations", 12);
+        debug!(method = "cg"; "converged");
+
+        let records = logs.records();
+        if cfg!(feature = "enable_trace") {
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].target(), "test");
+            assert_eq!(records[0].message(), "converged after 12 iterations");
+            assert_eq!(records[0].field("iter"), Some("12"));
+            assert_eq!(records[0].field("residual"), Some("1.5e-9"));
+            assert_eq!(records[1].field("method"), Some("cg"));
+        } else {
+            assert!(records.is_empty());
+        }
+    }
 }
 //}}}
@@ -0,0 +1,84 @@
+//! The global `log` max level, raised to `Trace` while anything may let through records the filter
+//! of the logger rejects.
+//!
+//! The logging macros reject records more verbose than the global max level with a single atomic
+//! load. Captures and scoped filters can let through records more verbose than the filter of the
+//! logger, so while any of them is alive, on any thread, the max level is raised and those records
+//! are checked in full instead. Once the last of them is gone the max level goes back to that of
+//! the logger.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+//}}}
+//{{{ std imports
+use std::sync::{Mutex, MutexGuard};
+//}}}
+//{{{ dep imports
+use log::LevelFilter;
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: constants
+/// The number of guards raising the max level, and the level to restore once there are none.
+static STATE: Mutex<State> = Mutex::new(State {
+    raised: 0,
+    level: LevelFilter::Off,
+});
+//}}}
+//{{{ collection: Raised
+//{{{ struct: State
+struct State {
+    raised: usize,
+    level: LevelFilter,
+}
+//}}}
+//{{{ struct: Raised
+/// Keeps the global max level raised to `Trace` until dropped.
+#[derive(Debug)]
+pub(crate) struct Raised {
+    _private: (),
+}
+//}}}
+//{{{ impl Drop for Raised
+impl Drop for Raised {
+    fn drop(&mut self) {
+        let mut state = lock();
+        state.raised -= 1;
+        if state.raised == 0 {
+            log::set_max_level(state.level);
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ fun: raise
+/// Raises the global max level to `Trace` until the returned guard is dropped.
+///
+/// The level in effect before the first guard, which may have been set by another logger, is
+/// restored after the last one.
+pub(crate) fn raise() -> Raised {
+    let mut state = lock();
+    if state.raised == 0 {
+        state.level = log::max_level();
+    }
+    state.raised += 1;
+    log::set_max_level(LevelFilter::Trace);
+    Raised { _private: () }
+}
+//}}}
+//{{{ fun: set
+/// Sets the global max level to that of the filter of the logger, or only records it to be
+/// restored later while the max level is raised.
+pub(crate) fn set(level: LevelFilter) {
+    let mut state = lock();
+    state.level = level;
+    if state.raised == 0 {
+        log::set_max_level(level);
+    }
+}
+//}}}
+//{{{ fun: lock
+fn lock() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|err| err.into_inner())
+}
+//}}}
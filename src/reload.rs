@@ -0,0 +1,283 @@
+//! Replacing the filter of the installed logger while the program runs.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::filter::{Filter, FilterParseError};
+use crate::max_level;
+use crate::sink::Sink;
+use crate::TopoHedralLogger;
+//}}}
+//{{{ std imports
+use std::fmt;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, PoisonError};
+use std::thread;
+use std::time::Duration;
+//}}}
+//{{{ dep imports
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: ReloadError
+//{{{ enum: ReloadError
+/// Error returned when the filter of the installed logger cannot be replaced.
+///
+/// The previous filter stays in effect whenever an error is returned.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The logger of this crate has not been installed with [`init`](crate::init).
+    NotInstalled,
+    /// There is no sink with the given name.
+    UnknownSink(String),
+    /// A filter directive is malformed.
+    Filter(FilterParseError),
+    /// A filter file could not be read.
+    Io(io::Error),
+}
+//}}}
+//{{{ impl fmt::Display for ReloadError
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::NotInstalled => {
+                f.write_str("the topohedral-tracing logger is not installed")
+            }
+            ReloadError::UnknownSink(name) => write!(f, "there is no sink named `{}`", name),
+            ReloadError::Filter(err) => err.fmt(f),
+            ReloadError::Io(err) => write!(f, "cannot read filter file: {}", err),
+        }
+    }
+}
+//}}}
+//{{{ impl std::error::Error for ReloadError
+impl std::error::Error for ReloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReloadError::NotInstalled | ReloadError::UnknownSink(_) => None,
+            ReloadError::Filter(err) => Some(err),
+            ReloadError::Io(err) => Some(err),
+        }
+    }
+}
+//}}}
+//{{{ impl From<FilterParseError> for ReloadError
+impl From<FilterParseError> for ReloadError {
+    fn from(err: FilterParseError) -> Self {
+        ReloadError::Filter(err)
+    }
+}
+//}}}
+//{{{ impl From<io::Error> for ReloadError
+impl From<io::Error> for ReloadError {
+    fn from(err: io::Error) -> Self {
+        ReloadError::Io(err)
+    }
+}
+//}}}
+//}}}
+//{{{ impl TopoHedralLogger
+impl TopoHedralLogger {
+    /// Replaces the filter of a sink, keeping the cached and global `log` max levels in step with
+    /// the filters of all sinks.
+    ///
+    /// Callers hold the `inputs` lock so that concurrent replacements cannot interleave.
+    fn replace_filter(&self, sink: &Sink, filter: Filter) {
+        sink.filter.store(Arc::new(filter));
+        let max_level = self.filter_max_level();
+        self.max_level.store(max_level as usize, Ordering::Relaxed);
+        if self.is_installed() {
+            max_level::set(max_level);
+        }
+    }
+
+    /// Replaces the filter of the sink called `name` with the given directives, failing on the
+    /// first malformed one.
+    pub(crate) fn set_filter(&self, name: &str, directives: &str) -> Result<(), ReloadError> {
+        let sink = self
+            .sinks
+            .iter()
+            .find(|sink| sink.name == name)
+            .ok_or_else(|| ReloadError::UnknownSink(name.to_string()))?;
+        let filter = Filter::parse(directives)?;
+        let _inputs = self.inputs.lock().unwrap_or_else(PoisonError::into_inner);
+        self.replace_filter(sink, filter);
+        Ok(())
+    }
+
+    /// Rebuilds the filter of every sink from the sources it was configured with.
+    ///
+    /// A sink with a malformed directive keeps its filter and the first such error is returned,
+    /// while the other sinks are still rebuilt. If `only_if_changed` is set, the filter of a sink
+    /// is only rebuilt when the environment variables or files it reads from differ from the last
+    /// time they were read.
+    pub(crate) fn reload(&self, only_if_changed: bool) -> Result<(), ReloadError> {
+        let builds = self
+            .sinks
+            .iter()
+            .map(|sink| Filter::from_sources(&sink.sources))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut inputs = self.inputs.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut result = Ok(());
+        for ((sink, mut build), inputs) in self.sinks.iter().zip(builds).zip(inputs.iter_mut()) {
+            if only_if_changed && *inputs == build.inputs {
+                continue;
+            }
+            *inputs = build.inputs;
+
+            if build.errors.is_empty() {
+                self.replace_filter(sink, build.filter);
+            } else if result.is_ok() {
+                result = Err(build.errors.swap_remove(0).into());
+            }
+        }
+        result
+    }
+}
+//}}}
+//{{{ fun: set_filter
+/// Replaces the filter of the installed logger with the given directives.
+///
+/// The directives use the `TOPO_LOG` syntax and replace every previously configured filter of the
+/// main sink at once. If any directive is malformed an error is returned and the filter is left
+/// unchanged. The change lasts until the next call to [`set_filter`] or [`reload`]. The filters
+/// of sinks added with [`TracingBuilder::sink`](crate::TracingBuilder::sink) are replaced with
+/// [`set_sink_filter`].
+pub fn set_filter(directives: &str) -> Result<(), ReloadError> {
+    crate::installed_logger()
+        .ok_or(ReloadError::NotInstalled)?
+        .set_filter("", directives)
+}
+//}}}
+//{{{ fun: set_sink_filter
+/// Replaces the filter of the sink called `name` with the given directives.
+///
+/// This behaves like [`set_filter`] for the sinks added with
+/// [`TracingBuilder::sink`](crate::TracingBuilder::sink), and returns
+/// [`ReloadError::UnknownSink`] if there is no such sink.
+pub fn set_sink_filter(name: &str, directives: &str) -> Result<(), ReloadError> {
+    crate::installed_logger()
+        .ok_or(ReloadError::NotInstalled)?
+        .set_filter(name, directives)
+}
+//}}}
+//{{{ fun: reload
+/// Rebuilds the filters of the installed logger from the sources they were configured with.
+///
+/// Environment variables such as `TOPO_LOG` and filter files are read again, while filters given
+/// in code are kept. If any directive is malformed an error is returned and the filter of that
+/// sink is left unchanged.
+pub fn reload() -> Result<(), ReloadError> {
+    crate::installed_logger()
+        .ok_or(ReloadError::NotInstalled)?
+        .reload(false)
+}
+//}}}
+//{{{ fun: spawn_watcher
+/// Starts a background thread which reloads the filter whenever its environment variables or
+/// files change, checking every `interval`.
+///
+/// Errors are reported on stderr once per change and leave the previous filter in effect.
+pub(crate) fn spawn_watcher(logger: &'static TopoHedralLogger, interval: Duration) {
+    let spawned = thread::Builder::new()
+        .name("topohedral-tracing-watch".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(err) = logger.reload(true) {
+                eprintln!("topohedral-tracing: keeping previous filter: {}", err);
+            }
+        });
+
+    if let Err(err) = spawned {
+        eprintln!("topohedral-tracing: cannot start filter watcher: {}", err);
+    }
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{SinkBuilder, TracingBuilder};
+    use log::{Level, LevelFilter, Log, Metadata};
+    use std::fs;
+
+    fn enabled(logger: &TopoHedralLogger, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn test_set_filter() {
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Warn)
+            .build()
+            .unwrap();
+        assert!(!enabled(&logger, "topohedral_mesh", Level::Debug));
+
+        logger.set_filter("", "topohedral_mesh=debug").unwrap();
+        assert!(enabled(&logger, "topohedral_mesh", Level::Debug));
+        assert!(!enabled(&logger, "topohedral_geom", Level::Error));
+
+        assert!(logger.set_filter("", "topohedral_mesh=dbug").is_err());
+        assert!(enabled(&logger, "topohedral_mesh", Level::Debug));
+    }
+
+    #[test]
+    fn test_set_sink_filter() {
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Warn)
+            .sink(SinkBuilder::new("geom").filter("topohedral_geom", LevelFilter::Info))
+            .build()
+            .unwrap();
+        assert_eq!(logger.filter_max_level(), LevelFilter::Info);
+
+        logger.set_filter("geom", "topohedral_geom=trace").unwrap();
+        assert!(enabled(&logger, "topohedral_geom", Level::Trace));
+        assert!(!enabled(&logger, "topohedral_mesh", Level::Info));
+        assert_eq!(
+            logger.max_level.load(Ordering::Relaxed),
+            LevelFilter::Trace as usize
+        );
+
+        assert!(matches!(
+            logger.set_filter("mesh", "topohedral_mesh=trace"),
+            Err(ReloadError::UnknownSink(_))
+        ));
+    }
+
+    #[test]
+    fn test_reload_from_file() {
+        let path = std::env::temp_dir().join(format!(
+            "topohedral-tracing-reload-{}.filter",
+            std::process::id()
+        ));
+        fs::write(&path, "# solver\ntopohedral_mesh=warn\n").unwrap();
+
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Error)
+            .config_file(&path)
+            .build()
+            .unwrap();
+        assert!(!enabled(&logger, "topohedral_mesh", Level::Info));
+
+        fs::write(&path, "topohedral_mesh=info # raised mid-run\n").unwrap();
+        logger.reload(true).unwrap();
+        assert!(enabled(&logger, "topohedral_mesh", Level::Info));
+        assert!(enabled(&logger, "topohedral_geom", Level::Error));
+
+        logger.set_filter("", "topohedral_mesh=off").unwrap();
+        logger.reload(true).unwrap();
+        assert!(!enabled(&logger, "topohedral_mesh", Level::Error));
+        logger.reload(false).unwrap();
+        assert!(enabled(&logger, "topohedral_mesh", Level::Info));
+
+        fs::write(&path, "topohedral_mesh=inf\n").unwrap();
+        assert!(logger.reload(true).is_err());
+        assert!(enabled(&logger, "topohedral_mesh", Level::Info));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
+//}}}
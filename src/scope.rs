@@ -0,0 +1,192 @@
+//! Filters overriding the filter of the logger on one thread for the duration of a closure.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::filter::{Filter, FilterParseError};
+use crate::max_level::{self, Raised};
+//}}}
+//{{{ std imports
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+//}}}
+//{{{ dep imports
+use log::LevelFilter;
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: constants
+/// The number of scoped filters in effect on any thread, so that threads without one pay a single
+/// load to find out.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The scoped filters in effect on this thread, innermost last.
+    static SCOPES: RefCell<Vec<Filter>> = const { RefCell::new(Vec::new()) };
+}
+//}}}
+//{{{ collection: Scope
+//{{{ struct: Scope
+/// Removes the innermost scoped filter when dropped, also when the closure panics.
+struct Scope {
+    /// Lets records more verbose than the filter of the logger get past the global max level.
+    _raised: Raised,
+}
+//}}}
+//{{{ impl Drop for Scope
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let _ = SCOPES.try_with(|scopes| scopes.borrow_mut().pop());
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+//}}}
+//}}}
+//{{{ fun: with_filter
+/// Runs `f` with the filter directives layered on top of the filter of the logger, on the
+/// current thread only.
+///
+/// The directives use the `TOPO_LOG` syntax, although any sampling rate is ignored. A target
+/// covered by one of them, or any target if they include `all`, is logged at the level they give,
+/// whether more or less verbose than the filter of the logger. Other targets are filtered as
+/// before. Scopes can be nested, the innermost directive covering a target winning, and the
+/// override applies to every sink. Records logged by other threads at the same time are not
+/// affected, so parallel tests or solver instances can each have their own verbosity:
+///
+/// ```
+/// use topohedral_tracing::{debug, with_filter};
+///
+/// fn refine() {
+///     debug!(target: "topohedral_mesh", "refining");
+/// }
+///
+/// with_filter("topohedral_mesh=trace", refine).unwrap();
+/// ```
+///
+/// While any scope is alive the global `log` max level is raised, so that records more verbose
+/// than the filter of the logger reach it, and records are checked against the full filter on
+/// every thread until the last scope ends. Returns an error, without calling `f`, if a directive
+/// is malformed.
+pub fn with_filter<R>(directives: &str, f: impl FnOnce() -> R) -> Result<R, FilterParseError> {
+    let filter = Filter::parse(directives)?;
+    SCOPES.with(|scopes| scopes.borrow_mut().push(filter));
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    let _scope = Scope {
+        _raised: max_level::raise(),
+    };
+    Ok(f())
+}
+//}}}
+//{{{ fun: overridden
+/// Whether any scoped filter is in effect on the current thread.
+#[inline]
+pub(crate) fn overridden() -> bool {
+    ACTIVE.load(Ordering::Relaxed) > 0
+        && SCOPES
+            .try_with(|scopes| !scopes.borrow().is_empty())
+            .unwrap_or(false)
+}
+//}}}
+//{{{ fun: level
+/// The level the scoped filters of the current thread give `target`, or `None` if none of them
+/// covers it.
+#[inline]
+pub(crate) fn level(target: &str) -> Option<LevelFilter> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    SCOPES
+        .try_with(|scopes| {
+            scopes
+                .borrow()
+                .iter()
+                .rev()
+                .find_map(|filter| filter.level(target))
+        })
+        .ok()
+        .flatten()
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::test_util::Buffer;
+    use crate::writer::Target;
+    use crate::TracingBuilder;
+    use log::{Level, Log, Metadata, Record};
+    use std::panic;
+    use std::thread;
+
+    #[test]
+    fn test_scoped_levels() {
+        assert_eq!(level("topohedral_mesh"), None);
+        with_filter("topohedral_mesh=trace,topohedral_geom=off", || {
+            assert_eq!(level("topohedral_mesh::delaunay"), Some(LevelFilter::Trace));
+            assert_eq!(level("topohedral_linalg"), None);
+
+            with_filter("all=info,topohedral_mesh::delaunay=warn", || {
+                assert_eq!(level("topohedral_mesh::delaunay"), Some(LevelFilter::Warn));
+                assert_eq!(level("topohedral_mesh"), Some(LevelFilter::Info));
+                assert_eq!(level("topohedral_linalg"), Some(LevelFilter::Info));
+            })
+            .unwrap();
+
+            assert_eq!(level("topohedral_geom"), Some(LevelFilter::Off));
+            let other = thread::spawn(|| level("topohedral_mesh")).join().unwrap();
+            assert_eq!(other, None);
+        })
+        .unwrap();
+        assert!(!overridden());
+    }
+
+    #[test]
+    fn test_scoped_filter_errors_and_panics() {
+        let mut called = false;
+        assert!(with_filter("topohedral_mesh=dbug", || called = true).is_err());
+        assert!(!called);
+
+        let result = panic::catch_unwind(|| {
+            with_filter("topohedral_mesh=trace", || panic!("refinement failed")).unwrap()
+        });
+        assert!(result.is_err());
+        assert_eq!(level("topohedral_mesh"), None);
+    }
+
+    #[test]
+    fn test_scoped_filter_sinks() {
+        let buffer = Buffer::default();
+        let logger = TracingBuilder::new()
+            .default_level(LevelFilter::Warn)
+            .format("{target} {msg}")
+            .writer(Target::Pipe(Box::new(buffer.clone())))
+            .build()
+            .unwrap();
+        let log = |target: &str, level: Level| {
+            let metadata = Metadata::builder().level(level).target(target).build();
+            if logger.enabled(&metadata) {
+                logger.log(
+                    &Record::builder()
+                        .args(format_args!("{}", level))
+                        .metadata(metadata)
+                        .build(),
+                );
+            }
+        };
+
+        log("topohedral_mesh", Level::Debug);
+        with_filter("topohedral_mesh=debug,topohedral_geom=error", || {
+            log("topohedral_mesh", Level::Debug);
+            log("topohedral_mesh", Level::Trace);
+            log("topohedral_geom", Level::Warn);
+            log("topohedral_linalg", Level::Warn);
+        })
+        .unwrap();
+        log("topohedral_mesh", Level::Debug);
+
+        let contents = buffer.contents();
+        assert_eq!(contents, "topohedral_mesh DEBUG\ntopohedral_linalg WARN\n");
+    }
+}
+//}}}
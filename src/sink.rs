@@ -0,0 +1,214 @@
+//! A single destination of the logger with its own filter, format and writer.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::background::Background;
+use crate::filter::{Filter, FilterSource};
+use crate::format::{Format, Origin, Timestamp};
+use crate::scope;
+use crate::testing::Fields;
+use crate::writer::Writer;
+//}}}
+//{{{ std imports
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+//}}}
+//{{{ dep imports
+use arc_swap::ArcSwap;
+use log::{Level, Metadata, Record};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Sink
+//{{{ struct: Sink
+/// One destination of the logger, built from a [`SinkBuilder`](crate::SinkBuilder).
+///
+/// Every record is offered to each sink of the logger, which writes it only if its own filter
+/// enables it.
+pub(crate) struct Sink {
+    /// The name the sink is addressed by, empty for the main sink.
+    pub(crate) name: String,
+    pub(crate) filter: ArcSwap<Filter>,
+    /// The inputs the filter was configured with, for rebuilding it on reload.
+    pub(crate) sources: Vec<FilterSource>,
+    pub(crate) format: Format,
+    pub(crate) timestamp: Timestamp,
+    /// Whether the level is colored.
+    pub(crate) color: bool,
+    pub(crate) writer: Arc<Writer>,
+    /// The thread writing the records, if they are not written by the thread logging them.
+    pub(crate) background: Option<Background>,
+    /// The last record written, if repeats of it are suppressed.
+    pub(crate) dedup: Option<Mutex<Option<Repeated>>>,
+    /// Shifts which records the sampled directives of the filter keep.
+    pub(crate) seed: u64,
+}
+//}}}
+//{{{ struct: Repeated
+/// A record written by a sink suppressing repeats, and the number of repeats suppressed since.
+pub(crate) struct Repeated {
+    /// The record as formatted without a timestamp or color, which its repeats match.
+    key: String,
+    level: Level,
+    target: String,
+    module: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    column: Option<u32>,
+    /// The thread the record was logged from, shown by the summary in place of the thread
+    /// writing it.
+    origin: Origin,
+    message: String,
+    /// The key-values of the record, with the values formatted with `Display`.
+    fields: Vec<(String, String)>,
+    count: u64,
+}
+//}}}
+//{{{ impl Sink
+impl Sink {
+    /// Whether the filter of this sink enables the record, unless a scoped filter on the current
+    /// thread covers its target.
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> bool {
+        match scope::level(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.load().enabled(metadata),
+        }
+    }
+
+    /// Whether to keep a record the filter of this sink enables, which is decided by the sampled
+    /// directives of the filter unless a scoped filter covers its target.
+    ///
+    /// Each call counts towards the sampling, so this is only asked once per record.
+    fn sampled(&self, metadata: &Metadata) -> bool {
+        scope::level(metadata.target()).is_some() || self.filter.load().sampled(metadata, self.seed)
+    }
+
+    /// Formats and writes the record if the filter of this sink enables it and keeps it.
+    ///
+    /// `column` is the column the record was logged from, which `log::Record` does not carry. The
+    /// record is always formatted on the calling thread, as the indentation of spans and the
+    /// thread shown are those of the thread logging it, and only the writing is handed to the
+    /// background thread if there is one.
+    ///
+    /// If repeats are suppressed, a record formatting the same as the last one apart from its
+    /// timestamp is only counted, and the count is written as `(repeated N times)` after the last
+    /// record once a different one arrives or the sink is flushed. The first record after a flush
+    /// is always written.
+    pub(crate) fn log(&self, record: &Record, column: Option<u32>, start: Instant) {
+        if !self.enabled(record.metadata()) || !self.sampled(record.metadata()) {
+            return;
+        }
+        let origin = Origin::current();
+        let Some(dedup) = &self.dedup else {
+            self.write(self.render(record, column, &origin, start));
+            return;
+        };
+
+        let mut key = String::new();
+        self.format
+            .render(record, column, &origin, None, false, &mut key);
+        let mut last = dedup.lock().unwrap_or_else(|err| err.into_inner());
+        match &mut *last {
+            Some(repeated) if repeated.key == key => repeated.count += 1,
+            _ => {
+                if let Some(repeated) = &*last {
+                    self.summarise(repeated, start);
+                }
+                let mut fields = Fields(Vec::new());
+                let _ = record.key_values().visit(&mut fields);
+                *last = Some(Repeated {
+                    key,
+                    level: record.level(),
+                    target: record.target().to_string(),
+                    module: record.module_path().map(str::to_string),
+                    file: record.file().map(str::to_string),
+                    line: record.line(),
+                    column,
+                    origin: origin.clone(),
+                    message: record.args().to_string(),
+                    fields: fields.0,
+                    count: 0,
+                });
+                self.write(self.render(record, column, &origin, start));
+            }
+        }
+    }
+
+    /// Flushes any buffered output, first writing the number of repeats suppressed and waiting
+    /// for the background thread to write out the records queued so far.
+    ///
+    /// The last record is forgotten, so that a repeat of it logged after the flush is written
+    /// rather than counted towards a summary that may never come.
+    pub(crate) fn flush(&self, start: Instant) {
+        if let Some(dedup) = &self.dedup {
+            let mut last = dedup.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(repeated) = last.take() {
+                self.summarise(&repeated, start);
+            }
+        }
+        if let Some(background) = &self.background {
+            background.wait();
+        }
+        let _ = self.writer.flush();
+    }
+
+    /// Formats the record logged from `origin` as a line.
+    fn render(
+        &self,
+        record: &Record,
+        column: Option<u32>,
+        origin: &Origin,
+        start: Instant,
+    ) -> String {
+        let time = self.timestamp.now(start);
+        let mut line = String::new();
+        self.format
+            .render(record, column, origin, time.as_ref(), self.color, &mut line);
+        line
+    }
+
+    /// Writes the line, or hands it to the background thread if there is one.
+    fn write(&self, line: String) {
+        match &self.background {
+            Some(background) => background.send(line),
+            None => {
+                let _ = self.writer.write_line(&line);
+            }
+        }
+    }
+
+    /// Writes the number of repeats of the record suppressed since it was written, if any, as a
+    /// record with the same level, location, thread and key-values.
+    ///
+    /// The summary is written by whichever thread logs the next record or flushes, so it is shown
+    /// as logged from the thread and span depth of the record it summarises instead.
+    fn summarise(&self, repeated: &Repeated, start: Instant) {
+        let times = match repeated.count {
+            0 => return,
+            1 => "once".to_string(),
+            count => format!("{} times", count),
+        };
+        let fields: Vec<(&str, &str)> = repeated
+            .fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let line = self.render(
+            &Record::builder()
+                .args(format_args!("{} (repeated {})", repeated.message, times))
+                .level(repeated.level)
+                .target(&repeated.target)
+                .module_path(repeated.module.as_deref())
+                .file(repeated.file.as_deref())
+                .line(repeated.line)
+                .key_values(&fields)
+                .build(),
+            repeated.column,
+            &repeated.origin,
+            start,
+        );
+        self.write(line);
+    }
+}
+//}}}
+//}}}
@@ -0,0 +1,202 @@
+//! Spans marking the entry to and exit from a region of code, created with [`span!`](crate::span).
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::{topo_log, Callsite};
+//}}}
+//{{{ std imports
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Instant;
+//}}}
+//{{{ dep imports
+use log::Level;
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+thread_local! {
+    /// The number of spans entered and not yet exited on this thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+//{{{ collection: Span
+//{{{ struct: Span
+/// Guard returned by [`span!`](crate::span) which logs the exit from the span when dropped.
+///
+/// While the guard is alive every record logged on the same thread is indented by one more level
+/// in text output. A span whose level is disabled, or which is compiled out without the
+/// `enable_trace` feature, logs nothing and does not indent. The guard cannot be sent to another
+/// thread, as the indentation is kept per thread.
+#[must_use = "the span is exited as soon as the guard is dropped"]
+pub struct Span {
+    inner: Option<Entered>,
+    /// Keeps the guard on the thread whose depth it changed.
+    _not_send: PhantomData<*const ()>,
+}
+//}}}
+//{{{ struct: Entered
+/// The state of a span which logged its entry.
+struct Entered {
+    target: String,
+    level: Level,
+    callsite: &'static Callsite,
+    name: &'static str,
+    /// The key-values of the span, each formatted as ` key=value`.
+    fields: String,
+    start: Instant,
+}
+//}}}
+//{{{ impl Span
+impl Span {
+    /// Logs the entry to a span and returns the guard which logs its exit.
+    ///
+    /// This is used by the [`span!`](crate::span) macro once the level has been found to be
+    /// enabled. `fields` are the key-values already formatted as ` key=value` pairs.
+    #[doc(hidden)]
+    pub fn enter(
+        target: &str,
+        level: Level,
+        callsite: &'static Callsite,
+        name: &'static str,
+        fields: fmt::Arguments,
+    ) -> Self {
+        let fields = fields.to_string();
+        topo_log(
+            target,
+            level,
+            callsite,
+            format_args!("enter {}{}", name, fields),
+        );
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+
+        Self {
+            inner: Some(Entered {
+                target: target.to_string(),
+                level,
+                callsite,
+                name,
+                fields,
+                start: Instant::now(),
+            }),
+            _not_send: PhantomData,
+        }
+    }
+
+    /// A span which logs nothing, for a disabled or compiled out [`span!`](crate::span).
+    pub fn none() -> Self {
+        Self {
+            inner: None,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Whether the entry to the span was logged.
+    pub fn is_entered(&self) -> bool {
+        self.inner.is_some()
+    }
+}
+//}}}
+//{{{ impl Drop for Span
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(span) = self.inner.take() {
+            DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+            topo_log(
+                &span.target,
+                span.level,
+                span.callsite,
+                format_args!(
+                    "exit {}{} elapsed={:.3?}",
+                    span.name,
+                    span.fields,
+                    span.start.elapsed()
+                ),
+            );
+        }
+    }
+}
+//}}}
+//{{{ impl fmt::Debug for Span
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.inner {
+            Some(span) => f.debug_tuple("Span").field(&span.name).finish(),
+            None => f.write_str("Span(none)"),
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ fun: depth
+/// The number of spans entered and not yet exited on the current thread.
+pub(crate) fn depth() -> usize {
+    DEPTH.with(Cell::get)
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::format::{Origin, Template};
+    use log::Record;
+
+    static CALLSITE: Callsite = Callsite::new(module_path!(), file!(), line!(), column!());
+
+    fn render_msg(msg: &str) -> String {
+        let mut out = String::new();
+        Template::parse("{msg}").unwrap().render(
+            &Record::builder().args(format_args!("{}", msg)).build(),
+            None,
+            &Origin::current(),
+            None,
+            false,
+            &mut out,
+        );
+        out
+    }
+
+    #[test]
+    fn test_span_depth() {
+        assert_eq!(depth(), 0);
+        {
+            let outer = Span::enter(
+                "topohedral_mesh",
+                Level::Debug,
+                &CALLSITE,
+                "refine",
+                format_args!(" level={}", 2),
+            );
+            assert!(outer.is_entered());
+            assert_eq!(render_msg("split"), "  split");
+            {
+                let _inner = Span::enter(
+                    "topohedral_mesh",
+                    Level::Debug,
+                    &CALLSITE,
+                    "split",
+                    format_args!(""),
+                );
+                let _disabled = Span::none();
+                assert_eq!(depth(), 2);
+                assert_eq!(render_msg("edge"), "    edge");
+            }
+            assert_eq!(depth(), 1);
+        }
+        assert_eq!(depth(), 0);
+        assert_eq!(render_msg("done"), "done");
+    }
+
+    #[test]
+    fn test_span_macro() {
+        let span = crate::span!(Level::Trace, "triangulate", cells = 12, depth = 1);
+        if !cfg!(feature = "enable_trace") {
+            assert!(!span.is_entered());
+        }
+        drop(span);
+        assert_eq!(depth(), 0);
+    }
+}
+//}}}
@@ -0,0 +1,452 @@
+//! Capturing records in memory, for asserting in tests that something was logged.
+//!
+//! ```
+//! use topohedral_tracing::{assert_logged, testing, warn, Level};
+//!
+//! fn invert(det: f64) -> Option<f64> {
+//!     if det == 0.0 {
+//!         warn!(det = det; "matrix is singular");
+//!         return None;
+//!     }
+//!     Some(1.0 / det)
+//! }
+//!
+//! let logs = testing::capture();
+//! assert_eq!(invert(0.0), None);
+//! if cfg!(feature = "enable_trace") {
+//!     assert_logged!(Level::Warn, contains "singular");
+//!     assert_eq!(logs.records()[0].field("det"), Some("0.0"));
+//! }
+//! ```
+//!
+//! A capture sees every record emitted while it is alive, before and whatever the filtering of
+//! the sinks. Records logged through the macros of this crate are captured whether or not a logger
+//! has been installed, those logged through the `log` crate once the logger of this crate is
+//! installed. As the macros are compiled out without the `enable_trace` feature, so are the
+//! records a test expects to capture.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::max_level::{self, Raised};
+//}}}
+//{{{ std imports
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+//}}}
+//{{{ dep imports
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Record};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: constants
+/// The number of captures alive on any thread, so that logging without one costs a single load.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// The captures receiving the records of every thread.
+static GLOBAL: Mutex<Vec<Arc<Records>>> = Mutex::new(Vec::new());
+
+/// The number of captures in [`GLOBAL`].
+static GLOBAL_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The captures receiving the records of this thread only.
+    static LOCAL: RefCell<Vec<Arc<Records>>> = const { RefCell::new(Vec::new()) };
+}
+//}}}
+//{{{ collection: CapturedRecord
+//{{{ struct: CapturedRecord
+/// A record kept by a [`Capture`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedRecord {
+    level: Level,
+    target: String,
+    message: String,
+    fields: Vec<(String, String)>,
+}
+//}}}
+//{{{ impl CapturedRecord
+impl CapturedRecord {
+    fn new(record: &Record) -> Self {
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        Self {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            fields: fields.0,
+        }
+    }
+
+    /// The level of the record.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// The target of the record.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The formatted message, without the key-values.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The key-values of the record, in order, with the values formatted with `Display`.
+    pub fn fields(&self) -> &[(String, String)] {
+        &self.fields
+    }
+
+    /// The value of the key-value `key`, formatted with `Display`.
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+//}}}
+//{{{ impl fmt::Display for CapturedRecord
+impl fmt::Display for CapturedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.level, self.target, self.message)?;
+        for (key, value) in &self.fields {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+//}}}
+//{{{ struct: Fields
+/// Visitor collecting the key-values of a record, with the values formatted with `Display`.
+pub(crate) struct Fields(pub(crate) Vec<(String, String)>);
+//}}}
+//{{{ impl VisitSource for Fields
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let float = value
+            .to_f64()
+            .filter(|_| value.to_u64().is_none() && value.to_i64().is_none());
+        let value = match float {
+            Some(float) => format!("{:?}", float),
+            None => value.to_string(),
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+//}}}
+//}}}
+//{{{ collection: Capture
+//{{{ struct: Records
+/// The records kept by one capture.
+#[derive(Default)]
+struct Records(Mutex<Vec<CapturedRecord>>);
+//}}}
+//{{{ impl Records
+impl Records {
+    fn lock(&self) -> MutexGuard<'_, Vec<CapturedRecord>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+//}}}
+//{{{ struct: Capture
+/// Guard returned by [`capture`] and [`capture_global`] which keeps the records logged while it
+/// is alive.
+///
+/// The guard cannot be sent to another thread, as a capture made with [`capture`] is registered
+/// with the thread which made it.
+#[must_use = "records are only captured while the guard is alive"]
+pub struct Capture {
+    records: Arc<Records>,
+    global: bool,
+    /// Lets the macros get past the global max level, so that they hand over every record.
+    _raised: Raised,
+    _not_send: PhantomData<*const ()>,
+}
+//}}}
+//{{{ impl Capture
+impl Capture {
+    fn new(global: bool) -> Self {
+        let records = Arc::new(Records::default());
+        if global {
+            lock_global().push(Arc::clone(&records));
+            GLOBAL_ACTIVE.fetch_add(1, Ordering::Relaxed);
+        } else {
+            LOCAL.with(|local| local.borrow_mut().push(Arc::clone(&records)));
+        }
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            records,
+            global,
+            _raised: max_level::raise(),
+            _not_send: PhantomData,
+        }
+    }
+
+    /// The records captured so far, oldest first.
+    pub fn records(&self) -> Vec<CapturedRecord> {
+        self.records.lock().clone()
+    }
+
+    /// Whether a record of the given level whose message contains `text` was captured.
+    pub fn contains(&self, level: Level, text: &str) -> bool {
+        self.records
+            .lock()
+            .iter()
+            .any(|record| record.level == level && record.message.contains(text))
+    }
+
+    /// Forgets the records captured so far.
+    pub fn clear(&self) {
+        self.records.lock().clear();
+    }
+}
+//}}}
+//{{{ impl Drop for Capture
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let remove = |captures: &mut Vec<Arc<Records>>| {
+            captures.retain(|records| !Arc::ptr_eq(records, &self.records));
+        };
+        if self.global {
+            remove(&mut lock_global());
+            GLOBAL_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            let _ = LOCAL.try_with(|local| remove(&mut local.borrow_mut()));
+        }
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+//}}}
+//{{{ impl fmt::Debug for Capture
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("records", &self.records.lock().len())
+            .field("global", &self.global)
+            .finish()
+    }
+}
+//}}}
+//}}}
+//{{{ fun: capture
+/// Captures the records logged on the current thread until the returned guard is dropped.
+///
+/// As the test harness runs every test on its own thread, tests running in parallel do not see
+/// each other's records.
+pub fn capture() -> Capture {
+    Capture::new(false)
+}
+//}}}
+//{{{ fun: capture_global
+/// Captures the records logged on every thread until the returned guard is dropped.
+///
+/// Like [`capture`] this only adds a destination for the records. What the sinks write, and what
+/// [`topo_enabled`](crate::topo_enabled) returns, stay the same on every thread.
+pub fn capture_global() -> Capture {
+    Capture::new(true)
+}
+//}}}
+//{{{ fun: capturing
+/// Whether a capture receives the records logged on the current thread.
+#[inline]
+pub(crate) fn capturing() -> bool {
+    ACTIVE.load(Ordering::Relaxed) > 0
+        && (GLOBAL_ACTIVE.load(Ordering::Relaxed) > 0
+            || LOCAL
+                .try_with(|local| !local.borrow().is_empty())
+                .unwrap_or(false))
+}
+//}}}
+//{{{ fun: deliver
+/// Hands a record to every capture receiving the records of the current thread.
+pub(crate) fn deliver(record: &Record) {
+    if !capturing() {
+        return;
+    }
+    let captured = CapturedRecord::new(record);
+    let _ = LOCAL.try_with(|local| {
+        for records in local.borrow().iter() {
+            records.lock().push(captured.clone());
+        }
+    });
+    for records in lock_global().iter() {
+        records.lock().push(captured.clone());
+    }
+}
+//}}}
+//{{{ fun: assert_logged
+/// Panics unless a capture visible from the current thread holds a record of the given level
+/// whose message contains `text`, or equals it if `exact` is set.
+///
+/// This is the implementation of [`assert_logged!`](crate::assert_logged).
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_logged(level: Level, text: &str, exact: bool) {
+    let mut records = Vec::new();
+    let mut captures = 0;
+    let _ = LOCAL.try_with(|local| {
+        for capture in local.borrow().iter() {
+            records.extend(capture.lock().iter().cloned());
+            captures += 1;
+        }
+    });
+    for capture in lock_global().iter() {
+        records.extend(capture.lock().iter().cloned());
+        captures += 1;
+    }
+
+    let matches = |record: &CapturedRecord| {
+        record.level == level
+            && if exact {
+                record.message == text
+            } else {
+                record.message.contains(text)
+            }
+    };
+    if captures == 0 {
+        panic!("no record was captured as no capture is active, see `testing::capture`");
+    }
+    if !records.iter().any(matches) {
+        let expected = if exact { "equal to" } else { "containing" };
+        let captured: Vec<String> = records.iter().map(ToString::to_string).collect();
+        panic!(
+            "no {} record with a message {} {:?} was logged, captured:\n{}",
+            level,
+            expected,
+            text,
+            captured.join("\n")
+        );
+    }
+}
+//}}}
+//{{{ fun: lock_global
+fn lock_global() -> MutexGuard<'static, Vec<Arc<Records>>> {
+    GLOBAL.lock().unwrap_or_else(|err| err.into_inner())
+}
+//}}}
+//{{{ macro: assert_logged
+/// Asserts that a record was captured by a [`testing::capture`](crate::testing::capture) alive
+/// on the current thread, or by a global capture.
+///
+/// The record must have the given level and a message containing the text, or equal to the
+/// message when `contains` is left out. On failure the captured records are listed.
+///
+/// ```
+/// use topohedral_tracing::{assert_logged, info, testing, Level};
+///
+/// let _logs = testing::capture();
+/// info!(iter = 12; "converged");
+/// if cfg!(feature = "enable_trace") {
+///     assert_logged!(Level::Info, "converged");
+///     assert_logged!(Level::Info, contains "conv");
+/// }
+/// ```
+#[macro_export]
+macro_rules! assert_logged {
+    ($level:expr, contains $text:expr $(,)?) => {
+        $crate::testing::assert_logged($level, &$text, false)
+    };
+    ($level:expr, $message:expr $(,)?) => {
+        $crate::testing::assert_logged($level, &$message, true)
+    };
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{topo_log, topo_log_kv, Callsite};
+    use std::thread;
+
+    static CALLSITE: Callsite = Callsite::new(module_path!(), file!(), line!(), column!());
+
+    #[test]
+    fn test_capture() {
+        let logs = capture();
+        assert!(crate::__private::enabled("topohedral_mesh", Level::Trace));
+        topo_log_kv(
+            "topohedral_mesh",
+            Level::Warn,
+            &CALLSITE,
+            format_args!("matrix is singular"),
+            &[("det", Value::from(0.0)), ("rows", Value::from(3u32))],
+        );
+        topo_log(
+            "topohedral_mesh",
+            Level::Debug,
+            &CALLSITE,
+            format_args!("done"),
+        );
+
+        let records = logs.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].level(), Level::Warn);
+        assert_eq!(records[0].target(), "topohedral_mesh");
+        assert_eq!(records[0].message(), "matrix is singular");
+        assert_eq!(records[0].field("det"), Some("0.0"));
+        assert_eq!(records[0].field("rows"), Some("3"));
+        assert!(logs.contains(Level::Debug, "don"));
+        assert_logged!(Level::Warn, contains "singular");
+        assert_logged!(Level::Debug, "done");
+
+        logs.clear();
+        assert!(logs.records().is_empty());
+    }
+
+    #[test]
+    fn test_capture_is_per_thread() {
+        let logs = capture();
+        thread::spawn(|| {
+            topo_log(
+                "topohedral_mesh",
+                Level::Info,
+                &CALLSITE,
+                format_args!("other"),
+            );
+        })
+        .join()
+        .unwrap();
+        assert!(logs.records().is_empty());
+        drop(logs);
+        assert!(LOCAL.with(|local| local.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_capture_global() {
+        let logs = capture_global();
+        thread::spawn(|| {
+            topo_log(
+                "topohedral_mesh",
+                Level::Info,
+                &CALLSITE,
+                format_args!("global"),
+            );
+        })
+        .join()
+        .unwrap();
+        assert!(logs.contains(Level::Info, "global"));
+    }
+
+    #[test]
+    #[should_panic(expected = "no WARN record with a message containing \"singular\"")]
+    fn test_assert_logged_fails() {
+        let _logs = capture();
+        topo_log(
+            "topohedral_mesh",
+            Level::Info,
+            &CALLSITE,
+            format_args!("singular"),
+        );
+        assert_logged!(Level::Warn, contains "singular");
+    }
+}
+//}}}
@@ -0,0 +1,117 @@
+//! Destinations the formatted records are written to.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+use crate::file::{FileTarget, RotatingFile};
+//}}}
+//{{{ std imports
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+//}}}
+//{{{ dep imports
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Target
+//{{{ enum: Target
+/// Where the formatted records are written.
+#[derive(Default)]
+pub enum Target {
+    /// Standard error, the default.
+    #[default]
+    Stderr,
+    /// Standard output.
+    Stdout,
+    /// A file, optionally rotated.
+    File(FileTarget),
+    /// Any other writer, e.g. an in-memory buffer.
+    Pipe(Box<dyn Write + Send>),
+}
+//}}}
+//{{{ impl fmt::Debug for Target
+impl fmt::Debug for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Stderr => f.write_str("Stderr"),
+            Target::Stdout => f.write_str("Stdout"),
+            Target::File(file) => f.debug_tuple("File").field(file).finish(),
+            Target::Pipe(_) => f.write_str("Pipe(..)"),
+        }
+    }
+}
+//}}}
+//}}}
+//{{{ collection: Writer
+//{{{ enum: Writer
+/// The synchronised writer a [`Target`] is turned into when the logger is built.
+pub(crate) enum Writer {
+    Stderr,
+    Stdout,
+    File(Mutex<RotatingFile>),
+    Pipe(Mutex<Box<dyn Write + Send>>),
+}
+//}}}
+//{{{ impl Writer
+impl Writer {
+    /// Opens the writer for a target, which for a file creates it if needed.
+    pub(crate) fn open(target: Target) -> io::Result<Self> {
+        Ok(match target {
+            Target::Stderr => Writer::Stderr,
+            Target::Stdout => Writer::Stdout,
+            Target::File(file) => Writer::File(Mutex::new(RotatingFile::open(file)?)),
+            Target::Pipe(pipe) => Writer::Pipe(Mutex::new(pipe)),
+        })
+    }
+
+    /// Whether the writer is one of the standard streams.
+    pub(crate) fn is_console(&self) -> bool {
+        matches!(self, Writer::Stderr | Writer::Stdout)
+    }
+
+    /// Whether the writer is a standard stream attached to a terminal.
+    pub(crate) fn is_terminal(&self) -> bool {
+        match self {
+            Writer::Stderr => io::stderr().is_terminal(),
+            Writer::Stdout => io::stdout().is_terminal(),
+            Writer::File(_) | Writer::Pipe(_) => false,
+        }
+    }
+
+    /// Writes a single formatted line, appending the newline.
+    ///
+    /// The standard streams go through `eprintln!` and `println!` so that the test harness can
+    /// capture them.
+    pub(crate) fn write_line(&self, line: &str) -> io::Result<()> {
+        match self {
+            Writer::Stderr => {
+                eprintln!("{}", line);
+                Ok(())
+            }
+            Writer::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Writer::File(file) => file
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .write_line(line),
+            Writer::Pipe(pipe) => {
+                let mut pipe = pipe.lock().unwrap_or_else(|err| err.into_inner());
+                writeln!(pipe, "{}", line)
+            }
+        }
+    }
+
+    /// Flushes any buffered output.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        match self {
+            Writer::Stderr => io::stderr().flush(),
+            Writer::Stdout => io::stdout().flush(),
+            Writer::File(file) => file.lock().unwrap_or_else(|err| err.into_inner()).flush(),
+            Writer::Pipe(pipe) => pipe.lock().unwrap_or_else(|err| err.into_inner()).flush(),
+        }
+    }
+}
+//}}}
+//}}}
@@ -0,0 +1,29 @@
+//! Fixtures shared by the integration tests.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ std imports
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+/// An in-memory writer for `Target::Pipe`, whose clones share the bytes written.
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    /// Everything written so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
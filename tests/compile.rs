@@ -0,0 +1,9 @@
+//! Compile tests using the macros from a separate crate, which imports nothing but the macros.
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_macros_compile() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/pass/*.rs");
+    cases.compile_fail("tests/ui/fail/*.rs");
+}
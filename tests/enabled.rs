@@ -0,0 +1,25 @@
+//! `topo_enabled` against the filter of the installed logger.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ dep imports
+use topohedral_tracing::{set_filter, topo_enabled, Level, TracingBuilder};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_topo_enabled() {
+    assert!(!topo_enabled("test", Level::Error));
+
+    TracingBuilder::new()
+        .parse_directives("all=warn,topohedral_mesh=debug")
+        .init()
+        .unwrap();
+    assert!(topo_enabled("test", Level::Warn));
+    assert!(!topo_enabled("test", Level::Info));
+    assert!(topo_enabled("topohedral_mesh::delaunay", Level::Debug));
+    assert!(!topo_enabled("topohedral_mesh::delaunay", Level::Trace));
+
+    set_filter("all=trace").unwrap();
+    assert!(topo_enabled("test", Level::Trace));
+    assert!(topo_enabled("topohedral_mesh::delaunay", Level::Trace));
+}
@@ -0,0 +1,30 @@
+//! The logger installed by `init`, which also receives the records of the `log` crate macros.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ dep imports
+use topohedral_tracing::{assert_logged, init, testing, FileTarget, Level, Target, TracingBuilder};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_global() {
+    std::env::set_var("TOPO_LOG", "all=info");
+    init().unwrap();
+    assert_eq!(log::max_level(), log::LevelFilter::Info);
+
+    let logs = testing::capture();
+    log::info!("from log {}", 5);
+    assert_eq!(logs.records().len(), 1);
+    assert_logged!(Level::Info, "from log 5");
+    drop(logs);
+
+    assert!(init().is_err());
+
+    let path = std::env::temp_dir().join(format!(
+        "topohedral-tracing-second-init-{}.log",
+        std::process::id()
+    ));
+    let second = TracingBuilder::new().writer(Target::File(FileTarget::new(&path)));
+    assert!(second.init().is_err());
+    assert!(!path.exists());
+}
@@ -0,0 +1,83 @@
+//! Functions wrapped with `#[instrument]`, which are traced only with the `enable_trace` feature.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+mod common;
+use common::Buffer;
+//}}}
+//{{{ dep imports
+use log::LevelFilter;
+use topohedral_tracing::{instrument, Target, TracingBuilder};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+struct Mesh {
+    cells: Vec<u32>,
+}
+
+impl Mesh {
+    #[instrument(level = "debug", skip(factor), ret)]
+    fn refine(&mut self, level: u32, factor: f64) -> usize {
+        if level < 1 {
+            self.refine(level + 1, factor);
+        }
+        self.cells.push((f64::from(level) * factor) as u32);
+        self.cells.len()
+    }
+}
+
+#[instrument(level = "info", err)]
+fn parse_cells(spec: &str) -> Result<u32, std::num::ParseIntError> {
+    let cells = spec.parse::<u32>()?;
+    Ok(cells * 2)
+}
+
+#[instrument(skip_all)]
+fn evens(limit: u32) -> impl Iterator<Item = u32> {
+    (0..limit).filter(|n| n % 2 == 0)
+}
+
+#[test]
+fn test_instrument() {
+    let buffer = Buffer::default();
+    TracingBuilder::new()
+        .default_level(LevelFilter::Trace)
+        .format("{level} {msg}")
+        .writer(Target::Pipe(Box::new(buffer.clone())))
+        .init()
+        .unwrap();
+
+    let mut mesh = Mesh { cells: Vec::new() };
+    assert_eq!(mesh.refine(0, 0.5), 2);
+    assert_eq!(parse_cells("21"), Ok(42));
+    assert!(parse_cells("many").is_err());
+    assert_eq!(evens(5).collect::<Vec<_>>(), [0, 2, 4]);
+
+    let contents = buffer.contents();
+    let lines: Vec<&str> = contents
+        .lines()
+        .map(|line| line.split(" elapsed=").next().unwrap())
+        .collect();
+    if cfg!(feature = "enable_trace") {
+        assert_eq!(
+            lines,
+            [
+                "DEBUG enter refine level=0",
+                "DEBUG   enter refine level=1",
+                "DEBUG     refine returned 1",
+                "DEBUG   exit refine level=1",
+                "DEBUG   refine returned 2",
+                "DEBUG exit refine level=0",
+                "INFO enter parse_cells spec=\"21\"",
+                "INFO exit parse_cells spec=\"21\"",
+                "INFO enter parse_cells spec=\"many\"",
+                "ERROR   parse_cells failed: ParseIntError { kind: InvalidDigit }",
+                "INFO exit parse_cells spec=\"many\"",
+                "TRACE enter evens",
+                "TRACE exit evens",
+            ]
+        );
+    } else {
+        assert!(lines.is_empty(), "{:?}", lines);
+    }
+}
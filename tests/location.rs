@@ -0,0 +1,51 @@
+//! The source location of records logged by the macros, which are only logged with the
+//! `enable_trace` feature.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+mod common;
+use common::Buffer;
+//}}}
+//{{{ dep imports
+use topohedral_tracing::{info, span, Level, LevelFilter, Target, TracingBuilder};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_location() {
+    let buffer = Buffer::default();
+    TracingBuilder::new()
+        .default_level(LevelFilter::Trace)
+        .format("{location} {module} {file}:{line}:{column} {msg}")
+        .writer(Target::Pipe(Box::new(buffer.clone())))
+        .init()
+        .unwrap();
+
+    info!("converged");
+    {
+        let _span = span!(Level::Debug, "refine");
+    }
+    log::info!("from log");
+
+    let contents = buffer.contents();
+    let lines: Vec<&str> = contents
+        .lines()
+        .map(|line| line.split(" elapsed=").next().unwrap())
+        .collect();
+    if cfg!(feature = "enable_trace") {
+        assert_eq!(
+            lines,
+            [
+                "tests/location.rs:24:5 location tests/location.rs:24:5 converged",
+                "tests/location.rs:26:21 location tests/location.rs:26:21 enter refine",
+                "tests/location.rs:26:21 location tests/location.rs:26:21 exit refine",
+                "tests/location.rs:28 location tests/location.rs:28:0 from log",
+            ]
+        );
+    } else {
+        assert_eq!(
+            lines,
+            ["tests/location.rs:28 location tests/location.rs:28:0 from log"]
+        );
+    }
+}
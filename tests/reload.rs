@@ -0,0 +1,29 @@
+//! Replacing the filter of the installed logger, which also moves the global `log` max level.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ dep imports
+use topohedral_tracing::{reload, set_filter, LevelFilter, ReloadError, TracingBuilder};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_reload() {
+    assert!(matches!(reload(), Err(ReloadError::NotInstalled)));
+
+    std::env::set_var("TOPO_LOG_RELOAD", "all=trace");
+    TracingBuilder::new()
+        .env_var("TOPO_LOG_RELOAD")
+        .init()
+        .unwrap();
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+
+    set_filter("all=warn,topohedral_mesh=debug").unwrap();
+    assert_eq!(log::max_level(), LevelFilter::Debug);
+
+    reload().unwrap();
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+
+    std::env::set_var("TOPO_LOG_RELOAD", "all=error");
+    reload().unwrap();
+    assert_eq!(log::max_level(), LevelFilter::Error);
+}
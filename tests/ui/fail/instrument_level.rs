@@ -0,0 +1,10 @@
+use topohedral_tracing::instrument;
+
+#[instrument(level = "verbose")]
+fn refine(mesh: &[u32]) -> usize {
+    mesh.len()
+}
+
+fn main() {
+    refine(&[]);
+}
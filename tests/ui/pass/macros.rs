@@ -0,0 +1,57 @@
+// Every macro used from a crate which neither depends on `log` by name nor imports `topo_log`,
+// with items of the same names in scope to check that the expansions do not pick them up.
+
+use topohedral_tracing::{
+    assert_logged, debug, error, info, instrument, span, testing, trace, warn, Level,
+};
+
+#[allow(dead_code)]
+mod log {}
+
+#[allow(dead_code)]
+fn topo_log() {}
+
+#[instrument(level = "debug", skip(cells), ret)]
+fn refine(cells: &[u32], depth: u32) -> usize {
+    let _span = span!(Level::Trace, "split", depth = depth);
+    let _target = span!(target: "topohedral_mesh", Level::Debug, "merge");
+    cells.len()
+}
+
+fn main() {
+    let iter = 12;
+    let residual = 1.5e-9;
+    let logs = testing::capture();
+
+    trace!("trace {}", 1);
+    trace!(target: "solver", "trace {}", 1);
+    debug!("debug");
+    debug!(target: "solver", iter = iter; "debug");
+    info!(iter = iter, residual = residual; "converged after {} iterations", iter);
+    info!(target: "solver", iter = iter, residual = residual; "converged");
+    warn!("warn {residual}");
+    warn!(target: "solver", "warn");
+    error!(method = "cg"; "error");
+    error!(target: "solver", "error {}", iter);
+    for i in 0..10 {
+        warn!(every = 4; "every {}", i);
+        warn!(once; target: "solver", iter = i; "once");
+        error!(per_second = 2; "per second");
+    }
+
+    assert_eq!(refine(&[1, 2, 3], 0), 3);
+    if cfg!(feature = "enable_trace") {
+        assert_logged!(Level::Info, contains "converged after 12");
+        assert_logged!(Level::Debug, "refine returned 3");
+        assert_logged!(Level::Warn, "every 8");
+        let count = |message: &str| {
+            logs.records()
+                .iter()
+                .filter(|record| record.message().starts_with(message))
+                .count()
+        };
+        assert_eq!(count("every"), 3);
+        assert_eq!(count("once"), 1);
+        assert_eq!(count("per second"), 2);
+    }
+}
@@ -0,0 +1,335 @@
+//! Procedural macros for `topohedral-tracing`.
+//!
+//! These are re-exported by `topohedral-tracing` and are meant to be used through it, e.g. as
+//! `#[topohedral_tracing::instrument]`.
+//--------------------------------------------------------------------------------------------------
+
+//{{{ crate imports
+//}}}
+//{{{ std imports
+//}}}
+//{{{ dep imports
+use proc_macro::TokenStream;
+use proc_macro2::{TokenStream as TokenStream2, TokenTree};
+use quote::{quote, ToTokens};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, LitStr, Pat, ReturnType};
+//}}}
+//--------------------------------------------------------------------------------------------------
+
+//{{{ collection: Args
+//{{{ struct: Args
+/// The arguments of the `instrument` attribute.
+#[derive(Default)]
+struct Args {
+    level: Option<Ident>,
+    target: Option<LitStr>,
+    name: Option<LitStr>,
+    skip: Vec<Ident>,
+    skip_all: bool,
+    ret: bool,
+    err: bool,
+}
+//}}}
+//{{{ impl Args
+impl Args {
+    /// Parses one `key = value`, `key(..)` or `key` argument of the attribute.
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("level") {
+            let level: LitStr = meta.value()?.parse()?;
+            self.level = Some(parse_level(&level)?);
+        } else if meta.path.is_ident("target") {
+            self.target = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("skip") {
+            meta.parse_nested_meta(|arg| match arg.path.get_ident() {
+                Some(ident) => {
+                    self.skip.push(ident.clone());
+                    Ok(())
+                }
+                None => Err(arg.error("expected an argument name")),
+            })?;
+        } else if meta.path.is_ident("skip_all") {
+            self.skip_all = true;
+        } else if meta.path.is_ident("ret") {
+            self.ret = true;
+        } else if meta.path.is_ident("err") {
+            self.err = true;
+        } else {
+            return Err(meta.error(
+                "expected one of `level`, `target`, `name`, `skip`, `skip_all`, `ret` or `err`",
+            ));
+        }
+        Ok(())
+    }
+}
+//}}}
+//}}}
+//{{{ fun: instrument
+/// Wraps a function in a span, as created by `span!`, which logs its entry and exit.
+///
+/// The entry record shows the arguments of the function with their `Debug` implementation,
+/// except for `self` and arguments which are not plain identifiers. The attribute takes these
+/// optional arguments:
+///
+/// - `level = "debug"`: the level of the span, `trace` by default.
+/// - `target = "..."`: the target of the span, the module path by default.
+/// - `name = "..."`: the name of the span, the name of the function by default.
+/// - `skip(a, b)`: arguments which are not logged, e.g. because they are large or not `Debug`.
+/// - `skip_all`: log no arguments.
+/// - `ret`: log the return value, or the `Ok` value together with `err`, with `Debug`.
+/// - `err`: log the error of a function returning `Result` at the error level, with `Debug`.
+///
+/// The attribute honours the `enable_trace` feature of the crate using it, in the same way as the
+/// logging macros: without it the function is emitted unchanged. Async and const functions are
+/// not supported.
+#[proc_macro_attribute]
+pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = Args::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    match expand(args, function) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+//}}}
+//{{{ fun: expand
+/// Emits the instrumented function under `enable_trace` and the original one otherwise.
+fn expand(args: Args, function: ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &function.sig;
+    if let Some(asyncness) = sig.asyncness {
+        return Err(syn::Error::new(
+            asyncness.span(),
+            "`instrument` does not support async functions",
+        ));
+    }
+    if let Some(constness) = sig.constness {
+        return Err(syn::Error::new(
+            constness.span(),
+            "`instrument` does not support const functions",
+        ));
+    }
+
+    let logged = logged_args(&args, sig.inputs.iter())?;
+    let level = match &args.level {
+        Some(level) => quote!(::topohedral_tracing::__private::Level::#level),
+        None => quote!(::topohedral_tracing::__private::Level::Trace),
+    };
+    let target = match &args.target {
+        Some(target) => target.to_token_stream(),
+        None => quote!(::core::module_path!()),
+    };
+    let name = match &args.name {
+        Some(name) => name.value(),
+        None => sig.ident.to_string(),
+    };
+
+    let fields: String = logged
+        .iter()
+        .map(|arg| format!(" {}={{:?}}", arg))
+        .collect();
+    let closure_output = match &sig.output {
+        ReturnType::Type(_, ty) if contains_impl(ty.to_token_stream()) => quote!(),
+        output => quote!(#output),
+    };
+    let log_result = log_result(&args, &target, &level, &name);
+
+    let attrs = &function.attrs;
+    let vis = &function.vis;
+    let block = &function.block;
+    Ok(quote! {
+        #[cfg(feature = "enable_trace")]
+        #(#attrs)*
+        #vis #sig {
+            static __TOPO_CALLSITE: ::topohedral_tracing::Callsite = ::topohedral_tracing::Callsite::new(
+                ::core::module_path!(),
+                ::core::file!(),
+                ::core::line!(),
+                ::core::column!(),
+            );
+            let __topo_span = if ::topohedral_tracing::__private::enabled(#target, #level) {
+                ::topohedral_tracing::Span::enter(
+                    #target,
+                    #level,
+                    &__TOPO_CALLSITE,
+                    #name,
+                    ::core::format_args!(#fields, #(#logged),*),
+                )
+            } else {
+                ::topohedral_tracing::Span::none()
+            };
+            #[allow(clippy::redundant_closure_call)]
+            let __topo_result = (move || #closure_output #block)();
+            #log_result
+            __topo_result
+        }
+
+        #[cfg(not(feature = "enable_trace"))]
+        #function
+    })
+}
+//}}}
+//{{{ fun: logged_args
+/// The names of the arguments shown in the entry record, checking that skipped ones exist.
+fn logged_args<'a>(
+    args: &Args,
+    inputs: impl Iterator<Item = &'a FnArg>,
+) -> syn::Result<Vec<Ident>> {
+    let names: Vec<Ident> = inputs
+        .filter_map(|input| match input {
+            FnArg::Typed(typed) => match &*typed.pat {
+                Pat::Ident(pat) => Some(pat.ident.clone()),
+                _ => None,
+            },
+            FnArg::Receiver(_) => None,
+        })
+        .collect();
+
+    if let Some(unknown) = args.skip.iter().find(|skip| !names.contains(skip)) {
+        return Err(syn::Error::new(
+            unknown.span(),
+            format!("there is no argument named `{}`", unknown),
+        ));
+    }
+    if args.skip_all {
+        return Ok(Vec::new());
+    }
+    Ok(names
+        .into_iter()
+        .filter(|name| !args.skip.contains(name))
+        .collect())
+}
+//}}}
+//{{{ fun: log_result
+/// The statements logging the return value or error, as requested by `ret` and `err`.
+fn log_result(
+    args: &Args,
+    target: &TokenStream2,
+    level: &TokenStream2,
+    name: &str,
+) -> TokenStream2 {
+    let log = |level: &TokenStream2, value: TokenStream2, message: &str| {
+        quote! {
+            if ::topohedral_tracing::__private::enabled(#target, #level) {
+                ::topohedral_tracing::topo_log(
+                    #target,
+                    #level,
+                    &__TOPO_CALLSITE,
+                    ::core::format_args!(#message, #name, #value),
+                );
+            }
+        }
+    };
+    let error = quote!(::topohedral_tracing::__private::Level::Error);
+    let log_ret = |value| log(level, value, "{} returned {:?}");
+    let log_err = log(&error, quote!(err), "{} failed: {:?}");
+
+    match (args.ret, args.err) {
+        (false, false) => quote!(),
+        (true, false) => log_ret(quote!(__topo_result)),
+        (false, true) => quote! {
+            if let Err(err) = &__topo_result {
+                #log_err
+            }
+        },
+        (true, true) => {
+            let log_ok = log_ret(quote!(value));
+            quote! {
+                match &__topo_result {
+                    Ok(value) => { #log_ok }
+                    Err(err) => { #log_err }
+                }
+            }
+        }
+    }
+}
+//}}}
+//{{{ fun: parse_level
+/// Parses the name of a level into the name of the `Level` variant.
+fn parse_level(level: &LitStr) -> syn::Result<Ident> {
+    let variant = match level.value().to_ascii_lowercase().as_str() {
+        "trace" => "Trace",
+        "debug" => "Debug",
+        "info" => "Info",
+        "warn" => "Warn",
+        "error" => "Error",
+        _ => {
+            return Err(syn::Error::new(
+                level.span(),
+                "expected one of `trace`, `debug`, `info`, `warn` or `error`",
+            ))
+        }
+    };
+    Ok(Ident::new(variant, level.span()))
+}
+//}}}
+//{{{ fun: contains_impl
+/// Whether a type mentions `impl Trait`, which cannot be written as the return type of a closure.
+fn contains_impl(tokens: TokenStream2) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => ident == "impl",
+        TokenTree::Group(group) => contains_impl(group.stream()),
+        _ => false,
+    })
+}
+//}}}
+//-------------------------------------------------------------------------------------------------
+//{{{ mod: tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use syn::parse::Parser;
+
+    fn parse_args(tokens: TokenStream2) -> syn::Result<Args> {
+        let mut args = Args::default();
+        syn::meta::parser(|meta| args.parse(meta)).parse2(tokens)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse_args(quote!(level = "DEBUG", skip(mesh, cache), ret, err)).unwrap();
+        assert_eq!(args.level.unwrap(), "Debug");
+        assert_eq!(args.skip.len(), 2);
+        assert!(args.ret && args.err && !args.skip_all);
+
+        assert!(parse_args(quote!(level = "verbose")).is_err());
+        assert!(parse_args(quote!(fields(a))).is_err());
+    }
+
+    #[test]
+    fn test_logged_args() {
+        let function: ItemFn = syn::parse2(quote!(
+            fn refine(&self, mesh: &Mesh, (a, b): (u8, u8), mut level: u32) {}
+        ))
+        .unwrap();
+
+        let args = parse_args(quote!(skip(mesh))).unwrap();
+        let logged = logged_args(&args, function.sig.inputs.iter()).unwrap();
+        assert_eq!(logged, ["level"]);
+
+        let args = parse_args(quote!(skip(meshes))).unwrap();
+        assert!(logged_args(&args, function.sig.inputs.iter()).is_err());
+    }
+
+    #[test]
+    fn test_expand_rejects_async() {
+        let function: ItemFn = syn::parse2(quote!(
+            async fn refine() {}
+        ))
+        .unwrap();
+        assert!(expand(Args::default(), function).is_err());
+    }
+
+    #[test]
+    fn test_contains_impl() {
+        assert!(contains_impl(quote!(Option<impl Iterator<Item = u32>>)));
+        assert!(!contains_impl(quote!(Result<Vec<u32>, String>)));
+    }
+}
+//}}}